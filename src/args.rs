@@ -1,4 +1,4 @@
-use clap::{Parser, arg, ValueHint};
+use clap::{Parser, Subcommand, arg, ValueHint};
 
 const DEFAULT_PROMPT: &str = r"
 Task: Transcribe the page from the provided book image.
@@ -15,33 +15,288 @@ This is an image of a cat.
 </image>
 ";
 
+const DEFAULT_TRANSLATE_PROMPT: &str = r"
+Task: Translate the following transcribed page to {lang}.
+
+- Keep the Markdown formatting and the <image> tags as they are.
+- Answer with the translation only.
+";
+
+const DEFAULT_REFUSAL_PROMPT: &str = r"
+Note: this is the user's own document, scanned for their personal archive. Transcribing it is the expected task.
+";
+
+const DEFAULT_FIGURE_PROMPT: &str = r"
+Task: Describe the provided figure, extracted from a book page.
+
+- Describe what the figure shows in detail: its type (photo, chart, diagram, table, ...), its content and any visible labels.
+- Transcribe any text in the figure exactly as it appears.
+- Do not add any headers or footers, keep just the description.
+";
+
+/// --page-width when neither it nor --dpi is given.
+pub const DEFAULT_PAGE_WIDTH: u16 = 1600;
+
+/// --quality when not given.
+pub const DEFAULT_JPEG_QUALITY: u8 = 85;
+
+#[derive(Subcommand, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Command {
+    /// Render a generated sample page and transcribe it, to check the
+    /// pdfium binding, the Ollama connection and the model end to end
+    Selftest,
+}
 
 #[derive(Parser, Debug)]
 pub struct Args {
+    #[command(subcommand)]
+    pub command: Option<Command>,
+
     #[arg(short = 'v', long = "verbose")]
     pub verbose: bool,
 
     #[arg(short = 'c', long = "show-content")]
     pub show_content: bool,
 
+    /// Write the text layer positions of each page to -page-NNNNNN.layout.json
+    #[arg(long = "layout-json")]
+    pub layout_json: bool,
+
+    /// Print nothing while running, only a final summary
+    #[arg(long = "summary-only")]
+    pub summary_only: bool,
+
     #[arg(short = 'l', long, default_value = "error")]
     pub log_level: String,
 
-    #[arg(short = 'w', long, default_value = "1600")]
-    pub page_width: u16,
+    /// Render width in pixels, whatever the page size (default 1600); ignored with --dpi.
+    /// The whole page, before any crop, is capped at 12000 pixels on either side
+    #[arg(short = 'w', long)]
+    pub page_width: Option<u16>,
+
+    /// Render at this resolution, from each page's physical size, instead of a fixed --page-width
+    /// (capped at 12000 pixels on either side)
+    #[arg(long = "dpi")]
+    pub dpi: Option<f32>,
+
+    /// Render scanned pages (one full-page image) at the image's own width
+    #[arg(long = "native-raster")]
+    pub native_raster: bool,
+
+    /// Bit depth of the PNG kept on disk; the image sent to the model stays 8-bit
+    #[arg(long = "bit-depth", value_name = "8|16", default_value = "8")]
+    pub bit_depth: crate::render::BitDepth,
+
+    /// Image format of the rendered pages, on disk and sent to the model: png, jpeg or webp
+    #[arg(long = "format", alias = "image-format", value_name = "FORMAT", default_value = "png")]
+    pub format: crate::render::ImageFormat,
+
+    /// Convert pages to gray before encoding, a quarter of the RGBA size
+    #[arg(long = "grayscale")]
+    pub grayscale: bool,
+
+    /// JPEG quality, from 0 to 100 (default 85); only applies with --format jpeg
+    #[arg(long = "quality", alias = "jpeg-quality", value_parser = clap::value_parser!(u8).range(0..=100))]
+    pub quality: Option<u8>,
+
+    /// Crop each page to the bounding box of its text layer (full page when it has none)
+    #[arg(long = "crop-to-text")]
+    pub crop_to_text: bool,
+
+    /// Crop each page to the bounding box of all its objects (full page when it has none)
+    #[arg(long = "crop-to-content", conflicts_with = "crop_to_text")]
+    pub crop_to_content: bool,
+
+    /// Margin kept around the cropped content, in PDF points
+    #[arg(long = "crop-padding", value_name = "POINTS", default_value = "12")]
+    pub crop_padding: f32,
+
+    /// Draw form fields and their filled-in values (checkboxes, radio buttons, text
+    /// fields) through pdfium's form-fill environment
+    #[arg(long = "render-forms")]
+    pub render_forms: bool,
+
+    /// Column layout hint: a column count, or "auto" to detect it from the text layer
+    #[arg(long = "columns", value_name = "N|auto")]
+    pub columns: Option<crate::columns::Columns>,
+
+    /// With --columns, send each column as its own image and join the results
+    #[arg(long = "split-columns", requires = "columns")]
+    pub split_columns: bool,
+
+    /// Skip pages that render blank (separator sheets, empty versos): nothing is written
+    /// for them and nothing is sent to Ollama
+    #[arg(long = "skip-blank")]
+    pub skip_blank: bool,
+
+    /// With --skip-blank, a page is blank when its mean luminance is within this much
+    /// of white, or its luminance varies by no more (standard deviation), both 0.0-1.0
+    #[arg(long = "blank-threshold", value_name = "SHARE", default_value = "0.004", requires = "skip_blank")]
+    pub blank_threshold: f64,
+
+    /// Record the tool version and render settings in each PNG (tEXt/iTXt chunks)
+    #[arg(long = "embed-metadata")]
+    pub embed_metadata: bool,
 
     #[arg(short = 'k', long)]
     pub keep: bool, // keep pages
 
+    /// Skip pages whose transcription already exists and is not empty; with --keep, also
+    /// reuse page images already on disk instead of rendering them again
+    #[arg(long = "resume")]
+    pub resume: bool,
+
+    /// Render every selected page to disk before sending the first request, and time
+    /// both phases (implies --keep)
+    #[arg(long = "prerender", conflicts_with = "figures_only")]
+    pub prerender: bool,
+
     #[arg(short = 's', long)]
     pub page_start: Option<usize>,
 
     #[arg(short = 'e', long)]
     pub page_end: Option<usize>,
 
+    /// Pages to process, as a list of pages and ranges: "1,4,7-12,20"
+    #[arg(long = "pages", value_name = "LIST", conflicts_with_all = ["page_start", "page_end"])]
+    pub pages: Option<String>,
+
+    /// Order pages are sent in: forward, reverse, or label (by printed page label)
+    #[arg(long = "page-order", value_name = "ORDER", default_value = "forward")]
+    pub page_order: crate::pages::PageOrder,
+
+    /// Reuse rendered pages across runs, keyed by PDF content and render settings
+    #[arg(long = "render-cache-dir", value_name = "DIR")]
+    pub render_cache_dir: Option<String>,
+
+    /// Write the PDF's bookmarks to FILE as a nested Markdown list with page numbers
+    #[arg(long = "dump-outline", value_name = "FILE")]
+    pub dump_outline: Option<String>,
+
+    /// Also write each document as one JSON file: {"file", "pages": [{"page", "text", "tokens"}]}.
+    /// With several inputs, the input's name is appended to the file name.
+    #[arg(long = "json-out", value_name = "PATH")]
+    pub json_out: Option<String>,
+
+    /// Pretty-print the --json-out document
+    #[arg(long = "json-pretty", requires = "json_out")]
+    pub json_pretty: bool,
+
+    /// Write an HTML proofreading page showing each page image next to its editable
+    /// transcription (implies --keep). With several inputs, the input's name is appended.
+    #[arg(long = "html-review", value_name = "FILE")]
+    pub html_review: Option<String>,
+
+    /// Also write the whole document as one Markdown file, pages in order separated by ---.
+    /// With several inputs, the input's name is appended to the file name.
+    #[arg(long = "merge", value_name = "FILE")]
+    pub merge: Option<String>,
+
+    /// Print each input's document information (title, author, dates...) and exit
+    /// without rendering. --merge and --combine files start with it as YAML front matter
+    #[arg(long = "metadata-only")]
+    pub metadata_only: bool,
+
+    /// With --merge, start each page with a "## Page N" heading
+    #[arg(long = "merge-headings", requires = "merge")]
+    pub merge_headings: bool,
+
+    /// Also write the whole document as <name>.md in the output directory, each page
+    /// preceded by a <!-- page N --> comment
+    #[arg(long = "combine")]
+    pub combine: bool,
+
+    /// Name of the --combine file in --output-dir, {stem} replaced by the input's name;
+    /// must differ between inputs
+    #[arg(
+        long = "combine-name",
+        value_name = "TEMPLATE",
+        requires = "combine",
+        default_value = crate::naming::DEFAULT_COMBINED_TEMPLATE
+    )]
+    pub combine_name: String,
+
+    /// End the --combine file with a summary: page count, model, date and a table of
+    /// the tokens each page took
+    #[arg(long = "append-summary", requires = "combine")]
+    pub append_summary: bool,
+
+    /// Compare each transcription with the page's text layer, when it has one, and
+    /// flag pages whose word overlap is below --verify-threshold
+    #[arg(long = "verify-against-text")]
+    pub verify_against_text: bool,
+
+    /// Word overlap (0 to 1) under which --verify-against-text flags a page
+    #[arg(long = "verify-threshold", value_name = "RATIO", default_value = "0.5")]
+    pub verify_threshold: f64,
+
+    /// Score each transcription against the file of the same name (or .txt) in DIR:
+    /// character and word error rates per page and on average, written to accuracy.json in --output-dir
+    #[arg(long = "ground-truth", value_name = "DIR")]
+    pub ground_truth: Option<String>,
+
+    /// Write the arrival time of every streamed response chunk to this CSV file
+    /// (file, page, backend, output, chunk, elapsed_ms, bytes), relative to the page's request
+    #[arg(long = "token-timing", value_name = "PATH")]
+    pub token_timing: Option<String>,
+
+    /// Record completed pages in this file, to resume an interrupted run
+    #[arg(long = "checkpoint", value_name = "PATH")]
+    pub checkpoint: Option<String>,
+
+    /// Write the checkpoint after this many completed pages
+    #[arg(long = "checkpoint-every", value_name = "PAGES", default_value = "10")]
+    pub checkpoint_every: usize,
+
+    /// Write the checkpoint at least this often, in seconds
+    #[arg(long = "checkpoint-interval", value_name = "SECONDS", default_value = "30")]
+    pub checkpoint_interval: u64,
+
+    /// Rewrite --json-out, failures.json, accuracy.json and the --checkpoint every SECONDS
+    /// while pages finish, so a crash loses at most that much. Each flush writes the whole
+    /// files again: short intervals on long documents cost I/O. Without it the reports are
+    /// only written at the end
+    #[arg(
+        long = "flush-interval-secs",
+        value_name = "SECONDS",
+        value_parser = clap::value_parser!(u64).range(1..)
+    )]
+    pub flush_interval_secs: Option<u64>,
+
+    /// Skip the pages already recorded in the --checkpoint file
+    #[arg(long = "resume-checkpoint", requires = "checkpoint")]
+    pub resume_checkpoint: bool,
+
+    /// Files written per response, comma separated: md, json, txt
+    #[arg(long = "output-format", value_delimiter = ',', default_value = "md")]
+    pub output_format: Vec<crate::output::OutputFormat>,
+
+    /// Pause before writing while the output filesystem has less than this many bytes free
+    #[arg(long = "min-free-disk", value_name = "BYTES")]
+    pub min_free_disk: Option<u64>,
+
+    /// With --min-free-disk, stop the run instead of pausing
+    #[arg(long = "abort-on-low-disk", requires = "min_free_disk")]
+    pub abort_on_low_disk: bool,
+
+    /// Password of encrypted input PDFs
+    #[arg(long = "password", env = "PDF_PASSWORD", hide_env_values = true)]
+    pub password: Option<String>,
+
+    /// JSON object mapping input files (as given, or by file name) to their password,
+    /// for batches of PDFs with different passwords. --password covers the others
+    #[arg(long = "password-file", value_name = "JSON")]
+    pub password_file: Option<String>,
+
+    /// Directory for the page images and transcriptions
     #[arg(short = 'o', long, default_value = "output")]
     pub output_dir: String,
 
+    /// Name of each page's files in --output-dir: {stem}, {page}, {page:0N}, {total} and
+    /// {ext} are replaced, and subdirectories are created ("{stem}/{page:04}.{ext}")
+    #[arg(long = "name-template", value_name = "TEMPLATE", default_value = crate::naming::DEFAULT_TEMPLATE)]
+    pub name_template: String,
+
     #[arg(long = "ls")]
     pub enum_models: bool,
 
@@ -51,15 +306,363 @@ pub struct Args {
     #[arg(short = 'u', long, default_value = "http://localhost:11434", value_delimiter = ',')]
     pub ollama_url: Vec<String>,
 
+    /// Most Ollama requests in flight at once (default: one per worker in --ollama-url)
+    #[arg(long = "concurrency", value_name = "N")]
+    pub concurrency: Option<usize>,
+
+    /// Seconds to wait for a connection to an Ollama server
+    #[arg(long = "connect-timeout", value_name = "SECONDS")]
+    pub connect_timeout: Option<u64>,
+
+    /// Seconds to wait for a response to start, and then between streamed chunks
+    #[arg(long = "request-timeout", alias = "read-timeout", value_name = "SECONDS")]
+    pub request_timeout: Option<u64>,
+
+    /// Bearer token sent to the Ollama servers, for those behind an authenticating proxy
+    #[arg(long = "api-key", env = "OLLAMA_API_KEY", hide_env_values = true)]
+    pub api_key: Option<String>,
+
+    /// Seconds a whole request may take, streaming included (default 0: no limit).
+    /// Long answers are cut off, --request-timeout only bounds the gaps between chunks
+    #[arg(long = "timeout-secs", value_name = "SECONDS", default_value = "0")]
+    pub timeout_secs: u64,
+
+    /// Times to retry a request that fails to connect, gets a server error or breaks off midway
+    #[arg(long = "max-retries", value_name = "N", default_value = "3")]
+    pub max_retries: usize,
+
+    /// Delay before the first retry, doubled for each of the next ones up to 30s (with jitter)
+    #[arg(long = "retry-base-delay", value_name = "MS", default_value = "500")]
+    pub retry_base_delay: u64,
+
+    /// Send the retries of a failed page to the next --ollama-url rather than the one
+    /// that failed (retries stay on the same server when there is only one)
+    #[arg(long = "retry-different-node")]
+    pub retry_different_node: bool,
+
+    /// Render pages that failed or came back empty again, up to N more times, each
+    /// time 50% wider (at most 6000px)
+    #[arg(
+        long = "retry-escalate-width",
+        value_name = "N",
+        conflicts_with = "prompt_token_budget"
+    )]
+    pub retry_escalate_width: Option<usize>,
+
     #[arg(long = "prompt", default_value = DEFAULT_PROMPT)]
     pub prompt: String,
 
+    /// Read the prompt from FILE, exactly as written, instead of --prompt
+    #[arg(long = "prompt-file", value_name = "FILE", conflicts_with = "prompt")]
+    pub prompt_file: Option<String>,
+
+    /// Send the prompt as a system message identical on every page, with per-page parts
+    /// (column hints, the image) after it, so Ollama can reuse the prompt's KV cache
+    /// instead of evaluating it again for each page
+    #[arg(long = "stable-prefix")]
+    pub stable_prefix: bool,
+
+    /// System message sent first in every request, e.g. to set the output language or format
+    #[arg(long = "system-prompt", value_name = "TEXT")]
+    pub system_prompt: Option<String>,
+
+    /// Use the prompt in FILE when running MODEL (repeatable, MODEL=FILE).
+    /// Takes precedence over --prompt for that model.
+    #[arg(long = "prompt-for", value_name = "MODEL=FILE")]
+    pub prompt_for: Vec<String>,
+
+    /// Detect each page's language from its text layer and report it
+    #[arg(long = "detect-language")]
+    pub detect_language: bool,
+
+    /// Use the prompt in FILE for pages detected as LANG (repeatable, implies --detect-language)
+    #[arg(long = "lang-prompt", value_name = "LANG=FILE")]
+    pub lang_prompt: Vec<String>,
+
+    /// Use MODEL for pages detected as LANG (repeatable, implies --detect-language)
+    #[arg(long = "lang-model", value_name = "LANG=MODEL")]
+    pub lang_model: Vec<String>,
+
+    /// Ask for JSON following the schema in FILE (Ollama's "format"), and fail pages whose
+    /// answer is not valid JSON
+    #[arg(
+        long = "format-schema",
+        value_name = "FILE",
+        conflicts_with_all = ["cleanup_prompt", "translate", "split_columns"]
+    )]
+    pub format_schema: Option<String>,
+
+    /// Transcribe each page with every prompt in these files and keep the longest answer
+    #[arg(long = "ensemble-prompts", value_name = "FILES", value_delimiter = ',')]
+    pub ensemble_prompts: Vec<String>,
+
+    /// Write each page's final Ollama response (done_reason, timings, thinking) to -page-NNNNNN.response.json
+    #[arg(long = "save-raw-response")]
+    pub save_raw_response: bool,
+
+    /// Send each transcription back, text only, with the prompt in FILE to clean it up
+    #[arg(long = "cleanup-prompt", value_name = "FILE")]
+    pub cleanup_prompt: Option<String>,
+
+    /// With --cleanup-prompt, also keep the first pass as -page-NNNNNN.raw.md
+    #[arg(long = "save-raw", requires = "cleanup_prompt")]
+    pub save_raw: bool,
+
+    /// Also translate each transcription to LANG, written to -page-NNNNNN.LANG.md
+    #[arg(long = "translate", value_name = "LANG")]
+    pub translate: Option<String>,
+
+    /// Translation instruction, {lang} is replaced with the --translate value
+    #[arg(long = "translate-prompt", default_value = DEFAULT_TRANSLATE_PROMPT)]
+    pub translate_prompt: String,
+
+    /// Retry pages the model refuses to transcribe, with --refusal-prompt added to the prompt
+    #[arg(long = "retry-on-refusal", value_name = "RETRIES", num_args = 0..=1, default_missing_value = "2")]
+    pub retry_on_refusal: Option<usize>,
+
+    /// Phrase marking a short response as a refusal, case-insensitive (comma separated, replaces the defaults)
+    #[arg(long = "refusal-patterns", value_name = "PHRASES", value_delimiter = ',', requires = "retry_on_refusal")]
+    pub refusal_patterns: Vec<String>,
+
+    /// Text put before the prompt when retrying a refused page
+    #[arg(long = "refusal-prompt", default_value = DEFAULT_REFUSAL_PROMPT)]
+    pub refusal_prompt: String,
+
+    /// Drop chatter such as "Here is the transcription:" from the start and end of responses
+    #[arg(long = "strip-boilerplate")]
+    pub strip_boilerplate: bool,
+
+    /// Line prefix treated as boilerplate, case-insensitive (repeatable, replaces the defaults)
+    #[arg(long = "boilerplate-phrase", value_name = "PHRASE", requires = "strip_boilerplate")]
+    pub boilerplate_phrase: Vec<String>,
+
+    /// Remove a printed page number left alone on the first or last line of a page
+    #[arg(long = "strip-page-numbers")]
+    pub strip_page_numbers: bool,
+
+    /// Send each embedded image instead of the rendered page, one output per figure
+    #[arg(long = "figures-only")]
+    pub figures_only: bool,
+
+    #[arg(long = "figure-prompt", default_value = DEFAULT_FIGURE_PROMPT)]
+    pub figure_prompt: String,
+
     #[arg(short = 'm', long, default_value = "qwen2.5vl:latest")]
     pub model: String,
 
+    /// Stop each answer after this many generated tokens (each column's answer with
+    /// --split-columns), marking the page truncated
     #[arg(long = "max-tokens", default_value = "1024")]
     pub max_tokens: usize,
 
+    /// Sampling seed. Requests already use temperature 0, so with a seed, runs against the
+    /// same model and Ollama version give byte-identical transcriptions
+    #[arg(long = "seed")]
+    pub seed: Option<i64>,
+
+    /// Context window size in tokens, for pages whose text the model's default cuts short
+    #[arg(long = "num-ctx", value_name = "TOKENS", value_parser = clap::value_parser!(i32).range(1..))]
+    pub num_ctx: Option<i32>,
+
+    /// When an answer stops because the context window is full, transcribe the page
+    /// again once with twice the --num-ctx, up to --max-num-ctx
+    #[arg(long = "auto-grow-ctx", requires = "num_ctx")]
+    pub auto_grow_ctx: bool,
+
+    /// Largest context window --auto-grow-ctx grows to
+    #[arg(
+        long = "max-num-ctx",
+        value_name = "TOKENS",
+        default_value = "32768",
+        requires = "auto_grow_ctx",
+        value_parser = clap::value_parser!(i32).range(1..)
+    )]
+    pub max_num_ctx: i32,
+
+    /// Warn (and ask on a terminal) when a rendered page has more pixels than this
+    #[arg(long = "warn-large-pixels", value_name = "PIXELS")]
+    pub warn_large_pixels: Option<u64>,
+
+    /// Warn (and ask on a terminal) when an encoded page is larger than this
+    #[arg(long = "warn-large-bytes", value_name = "BYTES")]
+    pub warn_large_bytes: Option<u64>,
+
+    /// Warn (and ask on a terminal) when a page's effective resolution is below
+    /// this, counting a scan's own resolution when rendering upscales it
+    #[arg(long = "min-effective-dpi", value_name = "DPI")]
+    pub min_effective_dpi: Option<f32>,
+
+    /// Do not ask for confirmation, continue past warnings
+    #[arg(short = 'y', long = "yes")]
+    pub yes: bool,
+
+    /// Stop a page's stream once its output exceeds this many bytes
+    #[arg(long = "max-output-bytes", value_name = "BYTES")]
+    pub max_output_bytes: Option<usize>,
+
+    /// Downscale the rendered page until prompt + image fit in this many tokens
+    /// (default with --num-ctx: what it leaves after a --max-tokens answer)
+    #[arg(long = "prompt-token-budget", value_name = "TOKENS")]
+    pub prompt_token_budget: Option<usize>,
+
+    /// Log a warning for pages that take longer than this many seconds
+    #[arg(long = "slow-page-threshold", value_name = "SECONDS")]
+    pub slow_page_threshold: Option<f64>,
+
+    /// Wait this long between sending successive pages; trades throughput for
+    /// steadier load on shared or thermally limited hardware
+    #[arg(long = "page-delay-ms", value_name = "MS")]
+    pub page_delay_ms: Option<u64>,
+
+    /// Abort the run once this many pages have failed (exit code 4)
+    #[arg(long = "max-failures", value_name = "N")]
+    pub max_failures: Option<usize>,
+
+    /// Stop the whole run after this many seconds (exit code 3)
+    #[arg(long = "max-runtime", value_name = "SECONDS")]
+    pub max_runtime: Option<u64>,
+
+    /// Serve Prometheus metrics on this address (e.g. 127.0.0.1:9100) while running
+    #[arg(long = "metrics-addr", value_name = "HOST:PORT")]
+    pub metrics_addr: Option<String>,
+
+    /// Print a one-line JSON progress snapshot to stderr at a fixed interval
+    #[arg(long = "progress-json")]
+    pub progress_json: bool,
+
+    /// Seconds between --progress-json snapshots
+    #[arg(long = "progress-interval", value_name = "SECONDS", default_value = "5")]
+    pub progress_interval: u64,
+
+    /// pdfium shared library, or the directory containing it
+    #[arg(long = "pdfium-path", env = "PDFIUM_PATH", value_name = "PATH")]
+    pub pdfium_path: Option<String>,
+
     #[arg(value_name = "FILES", num_args = 1.., value_hint = ValueHint::FilePath)]
     pub files: Vec<String>,
 }
+
+impl Args {
+    /// Tokens the prompt and the image may take: --prompt-token-budget, or
+    /// else what --num-ctx leaves once the answer has room for --max-tokens.
+    pub fn prompt_budget(&self) -> Option<usize> {
+        self.prompt_token_budget.or_else(|| {
+            self.num_ctx
+                .map(|num_ctx| (num_ctx as usize).saturating_sub(self.max_tokens))
+        })
+    }
+
+    /// The prompt to send with each page: the `--prompt-for` file mapped to
+    /// the selected model if any, otherwise `--prompt-file`, otherwise
+    /// `--prompt`.
+    pub fn resolve_prompt(&self) -> anyhow::Result<String> {
+        for mapping in &self.prompt_for {
+            let (model, file) = mapping.split_once('=').ok_or_else(|| {
+                anyhow::anyhow!("Invalid --prompt-for {:?}, expected MODEL=FILE", mapping)
+            })?;
+            if model == self.model {
+                return std::fs::read_to_string(file).map_err(|err| {
+                    anyhow::anyhow!("Cannot read prompt file {:?} for {}: {}", file, model, err)
+                });
+            }
+        }
+        if let Some(file) = &self.prompt_file {
+            return std::fs::read_to_string(file)
+                .map_err(|err| anyhow::anyhow!("Cannot read prompt file {:?}: {}", file, err));
+        }
+        Ok(self.prompt.clone())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn prompt_file(name: &str, text: &str) -> String {
+        let path = std::env::temp_dir().join(format!("pdftopng-{}-{}", std::process::id(), name));
+        std::fs::write(&path, text).unwrap();
+        path.to_string_lossy().into_owned()
+    }
+
+    fn resolve(args: &[&str]) -> String {
+        let args = Args::try_parse_from([&["pdftopng", "-m", "llava"], args].concat()).unwrap();
+        args.resolve_prompt().unwrap()
+    }
+
+    #[test]
+    fn prompt_precedence() {
+        let file = prompt_file("file.txt", "from file\n");
+        let for_model = prompt_file("for-llava.txt", "for llava");
+        let for_other = format!("other={}", prompt_file("for-other.txt", "for other"));
+        let for_llava = format!("llava={}", for_model);
+
+        assert_eq!(resolve(&["--prompt", "inline"]), "inline");
+        assert_eq!(resolve(&[]), DEFAULT_PROMPT);
+        // read exactly as written, trailing newline included
+        assert_eq!(resolve(&["--prompt-file", &file]), "from file\n");
+        assert_eq!(resolve(&["--prompt-file", &file, "--prompt-for", &for_other]), "from file\n");
+        assert_eq!(resolve(&["--prompt-file", &file, "--prompt-for", &for_llava]), "for llava");
+        assert_eq!(resolve(&["--prompt", "inline", "--prompt-for", &for_llava]), "for llava");
+    }
+
+    #[test]
+    fn prompt_file_conflicts_with_prompt() {
+        let parsed = Args::try_parse_from(["pdftopng", "--prompt", "a", "--prompt-file", "b"]);
+        assert!(parsed.is_err());
+    }
+
+    #[test]
+    fn num_ctx_must_be_positive() {
+        for value in ["-1", "0"] {
+            assert!(Args::try_parse_from(["pdftopng", "--num-ctx", value]).is_err());
+        }
+        let args = Args::try_parse_from(["pdftopng", "--num-ctx", "8192"]).unwrap();
+        assert_eq!(args.num_ctx, Some(8192));
+    }
+
+    #[test]
+    fn auto_grow_ctx_needs_num_ctx() {
+        assert!(Args::try_parse_from(["pdftopng", "--auto-grow-ctx"]).is_err());
+        assert!(Args::try_parse_from(["pdftopng", "--max-num-ctx", "65536"]).is_err());
+        let args =
+            Args::try_parse_from(["pdftopng", "--num-ctx", "4096", "--auto-grow-ctx"]).unwrap();
+        assert_eq!((args.auto_grow_ctx, args.max_num_ctx), (true, 32768));
+    }
+
+    #[test]
+    fn prompt_budget_defaults_to_the_context_left() {
+        let budget = |args: &[&str]| {
+            Args::try_parse_from([&["pdftopng"], args].concat())
+                .unwrap()
+                .prompt_budget()
+        };
+        assert_eq!(budget(&[]), None);
+        assert_eq!(budget(&["--num-ctx", "8192"]), Some(8192 - 1024));
+        assert_eq!(budget(&["--num-ctx", "8192", "--max-tokens", "9000"]), Some(0));
+        assert_eq!(
+            budget(&["--num-ctx", "8192", "--prompt-token-budget", "3000"]),
+            Some(3000)
+        );
+    }
+
+    #[test]
+    fn escalate_width_conflicts_with_token_budget() {
+        let args = [
+            "pdftopng",
+            "--retry-escalate-width",
+            "2",
+            "--prompt-token-budget",
+            "1000",
+        ];
+        assert!(Args::try_parse_from(args).is_err());
+        let args = Args::try_parse_from(["pdftopng", "--retry-escalate-width", "2"]).unwrap();
+        assert_eq!(args.retry_escalate_width, Some(2));
+    }
+
+    #[test]
+    fn missing_prompt_file_is_an_error() {
+        let args = Args::try_parse_from(["pdftopng", "--prompt-file", "/nonexistent/prompt"])
+            .unwrap();
+        assert!(args.resolve_prompt().is_err());
+    }
+}
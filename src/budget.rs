@@ -0,0 +1,35 @@
+// Rough token estimates used to keep a request inside the model's context.
+//
+// These are deliberately crude: ~4 characters per text token, and one token
+// per 28x28 pixel patch for the image, which is what qwen2.5vl and most
+// recent vision encoders use.
+
+const CHARS_PER_TOKEN: usize = 4;
+const IMAGE_PATCH: usize = 28;
+const MIN_WIDTH: u16 = 256;
+
+pub fn estimate_text_tokens(text: &str) -> usize {
+    text.chars().count().div_ceil(CHARS_PER_TOKEN)
+}
+
+pub fn estimate_image_tokens(width: usize, height: usize) -> usize {
+    width.div_ceil(IMAGE_PATCH) * height.div_ceil(IMAGE_PATCH)
+}
+
+/// Shrink `width` (keeping the page's aspect ratio) until the prompt plus
+/// the image fits in `budget` tokens. Returns the width to render at and
+/// the resulting estimate, which may still exceed the budget if the
+/// minimum width was reached.
+pub fn fit_width(prompt: &str, width: u16, aspect: f32, budget: usize) -> (u16, usize) {
+    let prompt_tokens = estimate_text_tokens(prompt);
+    let estimate = |w: u16| {
+        let height = (w as f32 * aspect).round() as usize;
+        prompt_tokens + estimate_image_tokens(w as usize, height)
+    };
+
+    let mut width = width;
+    while estimate(width) > budget && width > MIN_WIDTH {
+        width = ((width as f32 * 0.9) as u16).max(MIN_WIDTH);
+    }
+    (width, estimate(width))
+}
@@ -0,0 +1,38 @@
+use anyhow::Result;
+use sha2::{Digest, Sha256};
+use std::path::{Path, PathBuf};
+
+/// Encoded page images from earlier runs, keyed by the PDF's content and
+/// every render setting, so changing only the model or prompt skips pdfium.
+pub struct RenderCache {
+    dir: PathBuf,
+    key: String,
+}
+
+impl RenderCache {
+    pub fn new(dir: &Path, pdf: &Path, params: &str) -> Result<Self> {
+        let mut hasher = Sha256::new();
+        std::io::copy(&mut std::fs::File::open(pdf)?, &mut hasher)?;
+        hasher.update(params.as_bytes());
+        let key = format!("{:x}", hasher.finalize());
+
+        std::fs::create_dir_all(dir)?;
+        Ok(Self {
+            dir: dir.to_path_buf(),
+            key,
+        })
+    }
+
+    pub fn path(&self, page_no: usize) -> PathBuf {
+        self.dir.join(format!("{}-{:06}.bin", self.key, page_no))
+    }
+}
+
+/// Write through a temporary file so an interrupted run never leaves a
+/// truncated entry behind.
+pub fn store(path: &Path, bytes: &[u8]) -> Result<()> {
+    let tmp = path.with_extension("tmp");
+    std::fs::write(&tmp, bytes)?;
+    std::fs::rename(&tmp, path)?;
+    Ok(())
+}
@@ -0,0 +1,74 @@
+use anyhow::Result;
+use log::debug;
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    path::PathBuf,
+    time::{Duration, Instant},
+};
+
+#[derive(Debug, Default, Serialize, Deserialize)]
+struct State {
+    /// completed page numbers, per input file as given on the command line
+    files: BTreeMap<String, BTreeSet<usize>>,
+}
+
+/// Completed pages, persisted every `every_pages` pages or `interval`,
+/// whichever comes first, so a long run can be resumed.
+pub struct Checkpoint {
+    path: PathBuf,
+    state: State,
+    pending: usize,
+    last_flush: Instant,
+    every_pages: usize,
+    interval: Duration,
+}
+
+impl Checkpoint {
+    /// Start a checkpoint at `path`, picking up its content when `resume`.
+    pub fn open(path: &str, resume: bool, every_pages: usize, interval: Duration) -> Result<Self> {
+        let state = match std::fs::read_to_string(path) {
+            Ok(content) if resume => serde_json::from_str(&content)
+                .map_err(|err| anyhow::anyhow!("Invalid checkpoint {:?}: {}", path, err))?,
+            _ => State::default(),
+        };
+        Ok(Self {
+            path: PathBuf::from(path),
+            state,
+            pending: 0,
+            last_flush: Instant::now(),
+            every_pages,
+            interval,
+        })
+    }
+
+    pub fn is_done(&self, file: &str, page_no: usize) -> bool {
+        self.state
+            .files
+            .get(file)
+            .is_some_and(|pages| pages.contains(&page_no))
+    }
+
+    pub fn mark_done(&mut self, file: &str, page_no: usize) -> Result<()> {
+        self.state
+            .files
+            .entry(file.to_string())
+            .or_default()
+            .insert(page_no);
+        self.pending += 1;
+        if self.pending >= self.every_pages || self.last_flush.elapsed() >= self.interval {
+            self.flush()?;
+        }
+        Ok(())
+    }
+
+    /// Write through a temporary file, so a crash mid-write keeps the
+    /// previous checkpoint intact.
+    pub fn flush(&mut self) -> Result<()> {
+        crate::output::write_atomic(&self.path, serde_json::to_string_pretty(&self.state)?)?;
+        debug!("Checkpoint written to {:?}", self.path);
+        self.pending = 0;
+        self.last_flush = Instant::now();
+        Ok(())
+    }
+}
@@ -0,0 +1,201 @@
+use anyhow::Result;
+use pdfium_render::prelude::*;
+use std::io::Cursor;
+
+/// Horizontal resolution of the text-coverage histogram.
+const BINS: usize = 200;
+/// Narrowest gap between text that counts as a gutter, in bins.
+const MIN_GUTTER_BINS: usize = 3;
+
+/// `--columns` value: a fixed count, or detection from the text layer.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Columns {
+    Fixed(usize),
+    Auto,
+}
+
+impl std::str::FromStr for Columns {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if s == "auto" {
+            return Ok(Columns::Auto);
+        }
+        match s.parse::<usize>() {
+            Ok(n) if n >= 1 => Ok(Columns::Fixed(n)),
+            _ => Err(format!("expected a column count or \"auto\", got {:?}", s)),
+        }
+    }
+}
+
+/// Column boundaries as fractions of the page width, from 0.0 to 1.0.
+/// `Fixed` splits the page evenly; `Auto` looks for vertical gutters in the
+/// text layer and returns None when the page has no text objects.
+pub fn boundaries(columns: Columns, page: &PdfPage) -> Option<Vec<f32>> {
+    match columns {
+        Columns::Fixed(n) => Some((0..=n).map(|i| i as f32 / n as f32).collect()),
+        Columns::Auto => detect(page),
+    }
+}
+
+fn detect(page: &PdfPage) -> Option<Vec<f32>> {
+    let page_width = page.width().value;
+    if page_width <= 0.0 {
+        return None;
+    }
+
+    let mut coverage = [0usize; BINS];
+    let mut found = false;
+    for object in page.objects().iter() {
+        if object.as_text_object().is_none() {
+            continue;
+        }
+        let Ok(bounds) = object.bounds() else {
+            continue;
+        };
+        let to_bin = |x: f32| ((x / page_width * BINS as f32) as usize).min(BINS - 1);
+        for bin in &mut coverage[to_bin(bounds.left().value)..=to_bin(bounds.right().value)] {
+            *bin += 1;
+        }
+        found = true;
+    }
+    if !found {
+        return None;
+    }
+
+    // headings spanning the page still cross gutters, so tolerate a little
+    let max = *coverage.iter().max()?;
+    let is_gap = |count: usize| count * 20 <= max;
+    let first = coverage.iter().position(|c| !is_gap(*c))?;
+    let last = coverage.iter().rposition(|c| !is_gap(*c))?;
+
+    let mut bounds = vec![0.0];
+    let mut run_start = None;
+    for (bin, count) in coverage.iter().enumerate().take(last + 1).skip(first) {
+        match (is_gap(*count), run_start) {
+            (true, None) => run_start = Some(bin),
+            (false, Some(start)) => {
+                if bin - start >= MIN_GUTTER_BINS {
+                    bounds.push((start + bin) as f32 / 2.0 / BINS as f32);
+                }
+                run_start = None;
+            }
+            _ => {}
+        }
+    }
+    bounds.push(1.0);
+    Some(bounds)
+}
+
+/// Map boundaries from page fractions to fractions of the `left`..`right`
+/// part of the page, dropping the ones outside it.
+pub fn within(bounds: &[f32], left: f32, right: f32) -> Vec<f32> {
+    let mut mapped = vec![0.0];
+    mapped.extend(
+        bounds
+            .iter()
+            .map(|bound| (bound - left) / (right - left))
+            .filter(|bound| *bound > 0.0 && *bound < 1.0),
+    );
+    mapped.push(1.0);
+    mapped
+}
+
+/// Instruction appended to the prompt so the model keeps to reading order.
+pub fn prompt_hint(count: usize) -> String {
+    format!(
+        "\n- The page is laid out in {} columns. Transcribe each column from top to bottom, \
+         starting with the leftmost column, and never read across columns.\n",
+        count
+    )
+}
+
+/// Cut an encoded PNG into vertical strips at `bounds` (fractions of the
+/// width), returning one encoded PNG per column, left to right.
+pub fn split_png(buffer: &[u8], bounds: &[f32]) -> Result<Vec<Vec<u8>>> {
+    let mut reader = png::Decoder::new(Cursor::new(buffer)).read_info()?;
+    let mut pixels = vec![0; reader.output_buffer_size().unwrap_or_default()];
+    let info = reader.next_frame(&mut pixels)?;
+    let bytes_per_pixel = info.line_size / info.width as usize;
+
+    let mut strips = Vec::new();
+    for edges in bounds.windows(2) {
+        let x0 = (edges[0] * info.width as f32) as usize;
+        let x1 = ((edges[1] * info.width as f32) as usize).min(info.width as usize);
+        if x1 <= x0 {
+            continue;
+        }
+
+        let mut strip = Vec::with_capacity((x1 - x0) * bytes_per_pixel * info.height as usize);
+        for row in pixels.chunks(info.line_size).take(info.height as usize) {
+            strip.extend_from_slice(&row[x0 * bytes_per_pixel..x1 * bytes_per_pixel]);
+        }
+
+        let mut encoded = Vec::new();
+        let mut encoder = png::Encoder::new(&mut encoded, (x1 - x0) as u32, info.height);
+        encoder.set_color(info.color_type);
+        encoder.set_depth(info.bit_depth);
+        let mut writer = encoder.write_header()?;
+        writer.write_image_data(&strip)?;
+        writer.finish()?;
+        strips.push(encoded);
+    }
+    Ok(strips)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A `width`x2 RGBA PNG whose red channel is the column index.
+    fn striped_png(width: u32) -> Vec<u8> {
+        let row = (0..width)
+            .flat_map(|x| [x as u8, 0, 0, 255])
+            .collect::<Vec<_>>();
+        let mut encoded = Vec::new();
+        let mut encoder = png::Encoder::new(&mut encoded, width, 2);
+        encoder.set_color(png::ColorType::Rgba);
+        encoder.set_depth(png::BitDepth::Eight);
+        let mut writer = encoder.write_header().unwrap();
+        writer.write_image_data(&row.repeat(2)).unwrap();
+        writer.finish().unwrap();
+        encoded
+    }
+
+    /// Width and first pixel's red channel of an encoded strip.
+    fn strip_start(encoded: &[u8]) -> (u32, u8) {
+        let mut reader = png::Decoder::new(Cursor::new(encoded)).read_info().unwrap();
+        let mut pixels = vec![0; reader.output_buffer_size().unwrap_or_default()];
+        let info = reader.next_frame(&mut pixels).unwrap();
+        (info.width, pixels[0])
+    }
+
+    #[test]
+    fn parses_column_counts() {
+        assert_eq!("auto".parse(), Ok(Columns::Auto));
+        assert_eq!("3".parse(), Ok(Columns::Fixed(3)));
+        assert!("0".parse::<Columns>().is_err());
+        assert!("two".parse::<Columns>().is_err());
+    }
+
+    #[test]
+    fn maps_boundaries_into_the_crop() {
+        let mapped = within(&[0.0, 0.2, 0.6, 1.0], 0.3, 0.8);
+        assert_eq!(mapped.len(), 3);
+        assert_eq!((mapped[0], mapped[2]), (0.0, 1.0));
+        assert!((mapped[1] - 0.6).abs() < 1e-6);
+        // a gutter at the crop's edge leaves a single column
+        assert_eq!(within(&[0.0, 0.3, 1.0], 0.3, 0.8), [0.0, 1.0]);
+    }
+
+    #[test]
+    fn splits_left_to_right_across_the_whole_width() {
+        let strips = split_png(&striped_png(10), &[0.0, 0.35, 0.7, 1.0]).unwrap();
+        let starts = strips
+            .iter()
+            .map(|strip| strip_start(strip))
+            .collect::<Vec<_>>();
+        assert_eq!(starts, [(3, 0), (4, 3), (3, 7)]);
+        assert_eq!(starts.iter().map(|(width, _)| width).sum::<u32>(), 10);
+    }
+}
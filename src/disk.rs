@@ -0,0 +1,71 @@
+use anyhow::Result;
+use log::{info, warn};
+use std::{path::Path, time::Duration};
+
+/// How often to look again while paused on a full disk.
+const POLL_INTERVAL: Duration = Duration::from_secs(10);
+
+/// Bytes available to unprivileged users on the filesystem holding `path`.
+#[cfg(unix)]
+pub fn available_space(path: &Path) -> std::io::Result<u64> {
+    use std::{ffi::CString, os::unix::ffi::OsStrExt};
+
+    let path = CString::new(path.as_os_str().as_bytes())?;
+    let mut stat = std::mem::MaybeUninit::<libc::statvfs>::uninit();
+    // SAFETY: `path` is NUL terminated and `stat` is only read on success
+    let stat = unsafe {
+        if libc::statvfs(path.as_ptr(), stat.as_mut_ptr()) != 0 {
+            return Err(std::io::Error::last_os_error());
+        }
+        stat.assume_init()
+    };
+    #[allow(clippy::unnecessary_cast)]
+    Ok(stat.f_bavail as u64 * stat.f_frsize as u64)
+}
+
+#[cfg(not(unix))]
+pub fn available_space(_path: &Path) -> std::io::Result<u64> {
+    Err(std::io::Error::new(
+        std::io::ErrorKind::Unsupported,
+        "free space check is only available on unix",
+    ))
+}
+
+/// Wait until the filesystem holding `dir` has at least `min_free` bytes
+/// available, or fail right away when `abort` is set. A filesystem that
+/// cannot be queried is let through with a warning.
+pub async fn ensure_free(dir: &Path, min_free: u64, abort: bool) -> Result<()> {
+    let mut paused = false;
+    loop {
+        let available = match available_space(dir) {
+            Ok(available) => available,
+            Err(err) => {
+                warn!("Cannot check free space on {:?}: {}", dir, err);
+                return Ok(());
+            }
+        };
+        if available >= min_free {
+            if paused {
+                info!("{} bytes free on {:?}, resuming", available, dir);
+            }
+            return Ok(());
+        }
+
+        if abort {
+            return Err(anyhow::anyhow!(
+                "Only {} bytes free on {:?}, below --min-free-disk {}",
+                available,
+                dir,
+                min_free
+            ));
+        }
+        if !paused {
+            warn!(
+                "Only {} bytes free on {:?}, below --min-free-disk {}: paused until space is freed",
+                available, dir, min_free
+            );
+            paused = true;
+        }
+        tokio::time::sleep(POLL_INTERVAL).await;
+    }
+}
@@ -0,0 +1,173 @@
+use anyhow::Result;
+use log::debug;
+use reqwest::Client;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+/// Downloads so far, each saved in its own numbered directory so two URLs
+/// ending in the same name do not overwrite each other.
+static DOWNLOADS: AtomicUsize = AtomicUsize::new(0);
+
+pub fn is_url(input: &str) -> bool {
+    input.starts_with("http://") || input.starts_with("https://")
+}
+
+/// A PDF downloaded to the temp directory, removed when dropped.
+pub struct Download {
+    path: PathBuf,
+}
+
+impl Download {
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+}
+
+impl Drop for Download {
+    fn drop(&mut self) {
+        if let Err(err) = std::fs::remove_file(&self.path) {
+            debug!("Could not remove {:?}: {}", self.path, err);
+        }
+        if let Some(dir) = self.path.parent() {
+            let _ = std::fs::remove_dir(dir);
+            // only succeeds once the last download of this process is gone
+            if let Some(dir) = dir.parent() {
+                let _ = std::fs::remove_dir(dir);
+            }
+        }
+    }
+}
+
+/// Name a download of `url` is saved under: its last non-empty path
+/// segment, with a .pdf extension.
+pub fn file_name(url: &str) -> String {
+    let name = url
+        .split(['?', '#'])
+        .next()
+        .map(|url| url.split_once("://").map_or(url, |(_, rest)| rest))
+        .and_then(|rest| rest.split_once('/'))
+        .and_then(|(_, path)| path.rsplit('/').find(|name| !name.is_empty()))
+        .unwrap_or("download.pdf");
+    if name.to_lowercase().ends_with(".pdf") {
+        name.to_string()
+    } else {
+        format!("{}.pdf", name)
+    }
+}
+
+/// Download `url` to a temporary file named after the last path segment,
+/// so output names are derived the same way as for local files.
+pub async fn download_pdf(client: &Client, url: &str) -> Result<Download> {
+    let name = file_name(url);
+
+    debug!("Downloading {}", url);
+    // reqwest follows up to 10 redirects by default
+    let response = client.get(url).send().await?.error_for_status()?;
+
+    let content_type = response
+        .headers()
+        .get(reqwest::header::CONTENT_TYPE)
+        .and_then(|value| value.to_str().ok())
+        .unwrap_or_default()
+        .to_string();
+    if !content_type.is_empty()
+        && !content_type.starts_with("application/pdf")
+        && !content_type.starts_with("application/octet-stream")
+    {
+        return Err(anyhow::anyhow!(
+            "{} is not a PDF (content-type {})",
+            url,
+            content_type
+        ));
+    }
+
+    let bytes = response.bytes().await?;
+    let dir = std::env::temp_dir()
+        .join(format!("pdftopng-{}", std::process::id()))
+        .join(DOWNLOADS.fetch_add(1, Ordering::Relaxed).to_string());
+    std::fs::create_dir_all(&dir)?;
+    let path = dir.join(name);
+    std::fs::write(&path, &bytes)?;
+    debug!("Downloaded {} bytes to {:?}", bytes.len(), path);
+
+    Ok(Download { path })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn recognizes_urls() {
+        assert!(is_url("https://example.com/a.pdf"));
+        assert!(is_url("http://example.com/a.pdf"));
+        assert!(!is_url("ftp://example.com/a.pdf"));
+        assert!(!is_url("papers/https.pdf"));
+        assert!(!is_url("/tmp/http://a.pdf"));
+    }
+
+    #[test]
+    fn names_downloads_after_the_last_segment() {
+        assert_eq!(file_name("https://example.com/papers/a.pdf"), "a.pdf");
+        assert_eq!(file_name("https://example.com/A.PDF"), "A.PDF");
+        assert_eq!(file_name("https://example.com/a.pdf?page=2&v=1/2"), "a.pdf");
+        assert_eq!(file_name("https://example.com/a.pdf#page=3"), "a.pdf");
+        assert_eq!(
+            file_name("https://example.com/papers/report/"),
+            "report.pdf"
+        );
+        assert_eq!(file_name("https://example.com/view?id=42"), "view.pdf");
+        assert_eq!(file_name("https://example.com/"), "download.pdf");
+        assert_eq!(file_name("https://example.com"), "download.pdf");
+        assert_eq!(file_name("https://example.com?file=a.pdf"), "download.pdf");
+    }
+
+    /// Answer `count` requests with `body` as a PDF, one per connection.
+    async fn serve(body: &'static str, count: usize) -> String {
+        use tokio::io::{AsyncReadExt, AsyncWriteExt};
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap();
+        tokio::spawn(async move {
+            for _ in 0..count {
+                let (mut socket, _) = listener.accept().await.unwrap();
+                let mut request = Vec::new();
+                while !request.ends_with(b"\r\n\r\n") {
+                    let mut byte = [0];
+                    socket.read_exact(&mut byte).await.unwrap();
+                    request.push(byte[0]);
+                }
+                let response = format!(
+                    "HTTP/1.1 200 OK\r\ncontent-type: application/pdf\r\n\
+                     content-length: {}\r\nconnection: close\r\n\r\n{}",
+                    body.len(),
+                    body
+                );
+                socket.write_all(response.as_bytes()).await.unwrap();
+                socket.shutdown().await.unwrap();
+            }
+        });
+        format!("http://{}", address)
+    }
+
+    #[tokio::test]
+    async fn same_names_do_not_share_a_path() {
+        let url = serve("%PDF-1.4", 2).await;
+        let client = Client::new();
+        let first = download_pdf(&client, &format!("{}/a/report.pdf", url))
+            .await
+            .unwrap();
+        let second = download_pdf(&client, &format!("{}/b/report.pdf", url))
+            .await
+            .unwrap();
+        assert_ne!(first.path(), second.path());
+        assert_eq!(first.path().file_name(), second.path().file_name());
+        assert_eq!(std::fs::read(first.path()).unwrap(), b"%PDF-1.4");
+
+        let (first_path, second_path) = (first.path().to_owned(), second.path().to_owned());
+        drop(first);
+        assert!(!first_path.exists());
+        assert!(second_path.exists());
+        drop(second);
+        assert!(!second_path.exists());
+    }
+}
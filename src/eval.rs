@@ -0,0 +1,98 @@
+use std::path::Path;
+
+/// Character and word error rates of a transcription against a reference.
+#[derive(Debug, Clone, Copy)]
+pub struct Scores {
+    pub cer: f64,
+    pub wer: f64,
+}
+
+/// Reference transcription for the output file `name`: the file of the same
+/// name in `dir`, or with a .txt extension.
+pub fn reference(dir: &Path, name: &str) -> Option<String> {
+    let path = dir.join(name);
+    std::fs::read_to_string(&path)
+        .or_else(|_| std::fs::read_to_string(path.with_extension("txt")))
+        .ok()
+}
+
+/// Error rates of `hypothesis` against `reference`: edit distance divided by
+/// the reference length, over characters (whitespace collapsed) and words.
+pub fn score(reference: &str, hypothesis: &str) -> Scores {
+    let words = |text: &str| {
+        text.split_whitespace()
+            .map(str::to_string)
+            .collect::<Vec<_>>()
+    };
+    let (reference_words, hypothesis_words) = (words(reference), words(hypothesis));
+    let chars = |words: &[String]| words.join(" ").chars().collect::<Vec<_>>();
+    let (reference_chars, hypothesis_chars) = (chars(&reference_words), chars(&hypothesis_words));
+
+    let rate = |distance: usize, len: usize| distance as f64 / len.max(1) as f64;
+    Scores {
+        cer: rate(
+            edit_distance(&reference_chars, &hypothesis_chars),
+            reference_chars.len(),
+        ),
+        wer: rate(
+            edit_distance(&reference_words, &hypothesis_words),
+            reference_words.len(),
+        ),
+    }
+}
+
+/// Levenshtein distance: insertions, deletions and substitutions turning `a`
+/// into `b`.
+fn edit_distance<T: PartialEq>(a: &[T], b: &[T]) -> usize {
+    let mut previous = (0..=b.len()).collect::<Vec<_>>();
+    let mut current = vec![0; b.len() + 1];
+    for (i, a) in a.iter().enumerate() {
+        current[0] = i + 1;
+        for (j, b) in b.iter().enumerate() {
+            let substitution = previous[j] + usize::from(a != b);
+            current[j + 1] = substitution.min(previous[j + 1] + 1).min(current[j] + 1);
+        }
+        std::mem::swap(&mut previous, &mut current);
+    }
+    previous[b.len()]
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn chars(text: &str) -> Vec<char> {
+        text.chars().collect()
+    }
+
+    #[test]
+    fn edit_distance_counts_each_edit() {
+        assert_eq!(edit_distance(&chars("kitten"), &chars("kitten")), 0);
+        assert_eq!(edit_distance(&chars("kitten"), &chars("sitting")), 3);
+        assert_eq!(edit_distance(&chars(""), &chars("abc")), 3);
+        assert_eq!(edit_distance(&chars("abc"), &chars("")), 3);
+    }
+
+    #[test]
+    fn identical_text_scores_zero() {
+        let scores = score("The quick  brown\nfox", "The quick brown fox");
+        assert_eq!((scores.cer, scores.wer), (0.0, 0.0));
+    }
+
+    #[test]
+    fn empty_reference_counts_every_hypothesis_edit() {
+        let scores = score("", "");
+        assert_eq!((scores.cer, scores.wer), (0.0, 0.0));
+        // the reference length is taken as 1, not divided by zero
+        let scores = score("", "ab");
+        assert_eq!((scores.cer, scores.wer), (2.0, 1.0));
+    }
+
+    #[test]
+    fn substitution_and_insertion() {
+        // "cat" -> "bat" and " down" added
+        let scores = score("the cat sat", "the bat sat down");
+        assert!((scores.cer - 6.0 / 11.0).abs() < 1e-9);
+        assert!((scores.wer - 2.0 / 3.0).abs() < 1e-9);
+    }
+}
@@ -0,0 +1,37 @@
+use std::process::ExitCode;
+
+/// Exit status contract, so scripts can tell a partial run from a broken one.
+///
+/// | code | meaning                                                         |
+/// |------|-----------------------------------------------------------------|
+/// | 0    | every selected page was transcribed                             |
+/// | 1    | some pages failed, the others were written                      |
+/// | 2    | nothing usable was produced (bad arguments, unreadable input)  |
+/// | 3    | the run was stopped by `--max-runtime`                          |
+/// | 4    | the run was aborted by `--max-failures`                         |
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Exit {
+    Success = 0,
+    PartialFailure = 1,
+    TotalFailure = 2,
+    Timeout = 3,
+    Aborted = 4,
+}
+
+impl Exit {
+    pub fn from_pages(processed: usize, failed: usize) -> Self {
+        if failed == 0 {
+            Exit::Success
+        } else if failed < processed {
+            Exit::PartialFailure
+        } else {
+            Exit::TotalFailure
+        }
+    }
+}
+
+impl From<Exit> for ExitCode {
+    fn from(exit: Exit) -> Self {
+        ExitCode::from(exit as u8)
+    }
+}
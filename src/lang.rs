@@ -0,0 +1,112 @@
+use anyhow::Result;
+use pdfium_render::prelude::*;
+use std::collections::{BTreeMap, HashSet};
+
+/// Fewest stopwords a page needs before we trust a guess.
+const MIN_HITS: usize = 8;
+
+/// Common short words per language (ISO 639-1), frequent enough that a page
+/// of running text contains several of them.
+const STOPWORDS: &[(&str, &[&str])] = &[
+    (
+        "en",
+        &[
+            "the", "and", "of", "to", "in", "is", "that", "it", "was", "for", "with", "as", "on",
+            "are", "this", "be", "by", "not", "which", "have", "from",
+        ],
+    ),
+    (
+        "fr",
+        &[
+            "le", "la", "les", "des", "et", "un", "une", "du", "est", "que", "qui", "dans", "pour",
+            "pas", "sur", "au", "avec", "il", "elle", "ce", "sont",
+        ],
+    ),
+    (
+        "de",
+        &[
+            "der", "die", "das", "und", "ist", "nicht", "ein", "eine", "zu", "den", "mit", "sich",
+            "auf", "dem", "des", "auch", "es", "von", "sie", "wird", "für",
+        ],
+    ),
+    (
+        "es",
+        &[
+            "el", "la", "los", "las", "y", "de", "que", "en", "un", "una", "es", "por", "con",
+            "para", "del", "se", "no", "su", "al", "lo", "como",
+        ],
+    ),
+    (
+        "it",
+        &[
+            "il", "lo", "la", "gli", "le", "di", "che", "è", "e", "un", "una", "per", "non", "del",
+            "della", "con", "sono", "nel", "anche", "si", "da",
+        ],
+    ),
+    (
+        "pt",
+        &[
+            "o", "os", "as", "de", "que", "e", "do", "da", "em", "um", "uma", "para", "com", "não",
+            "no", "na", "se", "por", "mais", "dos", "das",
+        ],
+    ),
+    (
+        "nl",
+        &[
+            "de", "het", "een", "en", "van", "is", "dat", "niet", "op", "te", "zijn", "voor",
+            "met", "die", "ook", "aan", "er", "maar", "om", "wordt", "bij",
+        ],
+    ),
+];
+
+/// Text of every text object on the page, space separated.
+pub fn page_text(page: &PdfPage) -> String {
+    page.objects()
+        .iter()
+        .filter_map(|object| object.as_text_object().map(|text| text.text()))
+        .collect::<Vec<_>>()
+        .join(" ")
+}
+
+/// Guess the language of `text` by counting stopwords. Returns None when
+/// the text is too short or no language clearly wins.
+pub fn detect(text: &str) -> Option<&'static str> {
+    let words = text
+        .split(|c: char| !c.is_alphabetic())
+        .filter(|word| !word.is_empty())
+        .map(str::to_lowercase)
+        .collect::<Vec<_>>();
+
+    let mut scores = STOPWORDS
+        .iter()
+        .map(|(lang, stopwords)| {
+            let stopwords = stopwords.iter().copied().collect::<HashSet<_>>();
+            let hits = words
+                .iter()
+                .filter(|word| stopwords.contains(word.as_str()))
+                .count();
+            (hits, *lang)
+        })
+        .collect::<Vec<_>>();
+    scores.sort_unstable_by(|a, b| b.cmp(a));
+
+    let (best, lang) = scores[0];
+    let runner_up = scores[1].0;
+    // closely related languages share stopwords, ask for a clear margin
+    (best >= MIN_HITS && best * 2 >= runner_up * 3).then_some(lang)
+}
+
+/// Parse repeated `LANG=VALUE` options into a map.
+pub fn parse_mapping(values: &[String], option: &str) -> Result<BTreeMap<String, String>> {
+    values
+        .iter()
+        .map(|value| {
+            value
+                .split_once('=')
+                .map(|(lang, value)| (lang.to_lowercase(), value.to_string()))
+                .ok_or_else(|| {
+                    anyhow::anyhow!("Invalid {} {:?}, expected LANG=VALUE", option, value)
+                })
+        })
+        .collect()
+}
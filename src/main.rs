@@ -1,6 +1,17 @@
 use base64::Engine;
-use log::{debug, info, trace};
-use std::{path::Path, time::Instant};
+use log::{debug, error, info, trace, warn};
+use std::{
+    borrow::Cow,
+    collections::{BTreeMap, BTreeSet, HashMap},
+    io::{IsTerminal, Write},
+    path::{Path, PathBuf},
+    process::ExitCode,
+    sync::{
+        Arc, Mutex, PoisonError,
+        atomic::{AtomicBool, Ordering},
+    },
+    time::{Duration, Instant},
+};
 
 use anyhow::Result;
 use clap::Parser;
@@ -10,31 +21,944 @@ use progress_bar::{
     Color, Style, finalize_progress_bar, inc_progress_bar, init_progress_bar,
     set_progress_bar_action,
 };
+use tokio::task::{Id as TaskId, JoinError, JoinSet};
 
 mod args;
-use args::Args;
+use args::{Args, Command};
+
+mod budget;
+
+mod cache;
+
+mod checkpoint;
+use checkpoint::Checkpoint;
+
+mod columns;
+
+mod disk;
+
+mod download;
+
+mod eval;
+
+mod exit;
+
+mod lang;
+
+mod metadata;
+
+mod metrics;
+use metrics::Metrics;
+
+mod naming;
+
+mod render;
+
+mod review;
+
+mod schema;
+use render::{BitDepth, ImageFormat};
+
+mod selftest;
+use exit::Exit;
+
+mod outline;
+
+mod output;
+
+mod pages;
+
+mod postprocess;
 
 mod ollama;
-use ollama::{ChatMessage, GenerateOptions, OllamaClient, Role};
+use ollama::{
+    ChatMessage, GenerateOptions, OllamaClient, OllamaError, OllamaResponse, RetryPolicy, Role,
+    TokenCount,
+};
+
+/// Largest width or height, in pixels, of the bitmap drawn for a page.
+const MAX_RENDER_DIMENSION: f32 = 12_000.0;
+
+/// Width growth per --retry-escalate-width attempt.
+const ESCALATION_STEP: f32 = 1.5;
+
+/// Widest render a --retry-escalate-width attempt may ask for.
+const MAX_ESCALATED_WIDTH: u16 = 6_000;
+
+/// Set by --summary-only to silence per-page output.
+static SUMMARY_ONLY: AtomicBool = AtomicBool::new(false);
+
+/// println! that stays quiet under --summary-only.
+macro_rules! status {
+    ($($arg:tt)*) => {
+        if !SUMMARY_ONLY.load(Ordering::Relaxed) {
+            println!($($arg)*);
+        }
+    };
+}
+
+#[derive(Debug, Default)]
+struct PageOutcome {
+    page_no: usize,
+    tokens: usize,
+    /// the limit that cut the answer short, if one did
+    truncated: Option<&'static str>,
+    /// final text of the page's outputs, kept for --json-out
+    text: Option<String>,
+    /// word overlap with the text layer, with --verify-against-text
+    similarity: Option<f64>,
+    /// still refused after the --retry-on-refusal retries
+    refused: bool,
+    /// model that transcribed the page
+    model: String,
+    /// context window the page needed, when --auto-grow-ctx grew it
+    grown_ctx: Option<i32>,
+    /// every output of the page came back blank
+    empty: bool,
+}
+
+/// Results of one document, filled in as its page tasks finish.
+#[derive(Debug, Default)]
+struct DocumentResults {
+    /// page number to the limit that cut its answer short
+    truncated_pages: BTreeMap<usize, &'static str>,
+    /// page number to (text, tokens), with --json-out
+    texts: BTreeMap<usize, (String, usize)>,
+    /// word overlap of each page with its text layer, with --verify-against-text
+    similarities: BTreeMap<usize, f64>,
+    /// images sent for each page, with --html-review
+    images: BTreeMap<usize, Vec<PathBuf>>,
+    /// model of each page transcribed in this run, with --append-summary
+    models: BTreeMap<usize, String>,
+    /// page number of each running task, to name the page when it fails
+    task_pages: HashMap<TaskId, usize>,
+    /// pages that failed or came back blank, for --retry-escalate-width
+    retry_pages: BTreeSet<usize>,
+}
+
+#[derive(Debug, Default)]
+struct RunStats {
+    files: usize,
+    pages: usize,
+    failed: usize,
+    tokens: usize,
+    truncated: usize,
+    /// pages per detected language, with --detect-language
+    languages: BTreeMap<String, usize>,
+    /// (input, page, error) of every failed page
+    failures: Vec<(String, usize, String)>,
+    /// why the run was cut short, if it was
+    aborted: Option<String>,
+    /// (input, page) of pages the model kept refusing, with --retry-on-refusal
+    refused: Vec<(String, usize)>,
+    /// (input, page) of pages skipped as blank, with --skip-blank
+    blank: Vec<(String, usize)>,
+    /// (input, page, num_ctx) of pages --auto-grow-ctx transcribed again
+    grown_ctx: Vec<(String, usize, i32)>,
+}
+
+/// Images of a page, removed when dropped so they go away as soon as the
+/// page task ends, even when it panics.
+struct PageImages(Vec<PathBuf>);
+
+impl Drop for PageImages {
+    fn drop(&mut self) {
+        for path in &self.0 {
+            if let Err(err) = std::fs::remove_file(path) {
+                warn!("Cannot remove {:?}: {}", path, err);
+            }
+        }
+    }
+}
 
 #[tokio::main]
-async fn main() -> Result<()> {
-    let args = Args::try_parse()?;
+async fn main() -> ExitCode {
+    // clap already exits with 2 on usage errors, matching Exit::TotalFailure
+    let args = Args::parse();
 
     env_logger::builder()
         .filter_level(args.log_level.parse().unwrap())
         .init();
 
+    let outcome = match args.max_runtime {
+        Some(secs) => match tokio::time::timeout(Duration::from_secs(secs), run(args)).await {
+            Ok(outcome) => outcome,
+            Err(_) => {
+                eprintln!("Run stopped after reaching --max-runtime of {}s", secs);
+                return Exit::Timeout.into();
+            }
+        },
+        None => run(args).await,
+    };
+
+    match outcome {
+        Ok(stats) => {
+            if stats.failed > 0 {
+                eprintln!("{} of {} pages failed:", stats.failed, stats.pages);
+                for (file, page, error) in &stats.failures {
+                    eprintln!("  {} page {}: {}", file, page, error);
+                }
+            }
+            if !stats.refused.is_empty() {
+                eprintln!("{} pages were refused by the model:", stats.refused.len());
+                for (file, page) in &stats.refused {
+                    eprintln!("  {} page {}", file, page);
+                }
+            }
+            if !stats.grown_ctx.is_empty() {
+                eprintln!(
+                    "{} pages needed a larger context window:",
+                    stats.grown_ctx.len()
+                );
+                for (file, page, num_ctx) in &stats.grown_ctx {
+                    eprintln!("  {} page {}: --num-ctx {}", file, page, num_ctx);
+                }
+            }
+            if !stats.blank.is_empty() && !SUMMARY_ONLY.load(Ordering::Relaxed) {
+                println!("Skipped {} blank pages", stats.blank.len());
+            }
+            if stats.aborted.is_some() {
+                return Exit::Aborted.into();
+            }
+            Exit::from_pages(stats.pages, stats.failed).into()
+        }
+        Err(err) => {
+            eprintln!("Error: {:?}", err);
+            Exit::TotalFailure.into()
+        }
+    }
+}
+
+/// Warn about an oversized or low-quality render and, on an interactive
+/// terminal without `--yes`, ask whether to go on.
+fn confirm_large(args: &Args, message: String) -> Result<()> {
+    warn!("{}", message);
+    if args.yes || !std::io::stdin().is_terminal() {
+        return Ok(());
+    }
+
+    eprint!("{}. Continue? [y/N] ", message);
+    let mut answer = String::new();
+    std::io::stdin().read_line(&mut answer)?;
+    if matches!(answer.trim(), "y" | "Y" | "yes") {
+        Ok(())
+    } else {
+        Err(anyhow::anyhow!("Aborted: {}", message))
+    }
+}
+
+/// The region of the page to render, with --crop-to-text or
+/// --crop-to-content. None renders the whole page.
+fn page_crop(args: &Args, page: &PdfPage) -> Option<render::Crop> {
+    if args.crop_to_text {
+        render::text_crop(page, args.crop_padding)
+    } else if args.crop_to_content {
+        render::content_crop(page, args.crop_padding)
+    } else {
+        None
+    }
+}
+
+/// Render a page at the configured width, times `width_scale` on an
+/// escalated retry, and encode it, warning about oversized results along the
+/// way. None for a blank page with --skip-blank.
+fn render_page(
+    args: &Args,
+    prompt: &str,
+    page: &PdfPage,
+    page_no: usize,
+    width_scale: f32,
+) -> Result<Option<Vec<u8>>> {
+    let crop = page_crop(args, page);
+    // 16-bit output is averaged down from a render at twice the size
+    let depth_scale = match args.bit_depth {
+        BitDepth::Eight => 1.0,
+        BitDepth::Sixteen => 2.0,
+    };
+    let mut page_width = match args.dpi {
+        // the kept region at the requested resolution, points being 1/72 inch
+        Some(dpi) => {
+            let region_width = crop.map_or(1.0, |crop| crop.right - crop.left);
+            let width = page.width().value * region_width * dpi / 72.0;
+            // posters at high dpi would make bitmaps of several gigabytes; the
+            // whole page is drawn before cropping, and twice as large for 16-bit
+            let render_width = page.width().value * dpi / 72.0 * depth_scale;
+            let render_height = page.height().value * dpi / 72.0 * depth_scale;
+            let scale = (MAX_RENDER_DIMENSION / render_width.max(render_height)).min(1.0);
+            if scale < 1.0 {
+                warn!(
+                    "Page {}: a {}x{} render at {} dpi exceeds {} pixels, rendering at {:.0} dpi",
+                    page_no,
+                    render_width.round(),
+                    render_height.round(),
+                    dpi,
+                    MAX_RENDER_DIMENSION,
+                    dpi * scale
+                );
+            }
+            (width * scale).round().max(1.0) as u16
+        }
+        None => args.page_width.unwrap_or(args::DEFAULT_PAGE_WIDTH),
+    };
+    if args.native_raster {
+        match render::native_raster_width(page) {
+            Some(width) => {
+                debug!(
+                    "Page {}: single raster, rendering at native {}px",
+                    page_no, width
+                );
+                page_width = width;
+            }
+            None => debug!("Page {}: not a single full-page raster", page_no),
+        }
+    }
+    if width_scale > 1.0 {
+        let width = (page_width as f32 * width_scale).min(MAX_ESCALATED_WIDTH as f32) as u16;
+        status!(
+            "Page {}: retrying at {}px instead of {}px",
+            page_no,
+            width.max(page_width),
+            page_width
+        );
+        page_width = width.max(page_width);
+    }
+
+    if let Some(budget) = args.prompt_budget() {
+        let aspect = match crop {
+            Some(crop) => {
+                (crop.bottom - crop.top) * page.height().value
+                    / ((crop.right - crop.left) * page.width().value)
+            }
+            None => page.height().value / page.width().value,
+        };
+        let (width, estimate) = budget::fit_width(prompt, page_width, aspect, budget);
+        if width < page_width {
+            info!(
+                "Page {}: downscaled render from {}px to {}px to fit the {} token budget",
+                page_no, page_width, width, budget
+            );
+        }
+        if estimate > budget {
+            warn!(
+                "Page {}: estimated {} tokens still exceeds the {} token budget",
+                page_no, estimate, budget
+            );
+        }
+        page_width = width;
+    }
+
+    if let Some(min_dpi) = args.min_effective_dpi {
+        let region_width = match crop {
+            Some(crop) => (crop.right - crop.left) * page.width().value,
+            None => page.width().value,
+        };
+        let render_dpi = page_width as f32 / (region_width / 72.0);
+        // a scan holds no more detail than its own pixels
+        let native_dpi = render::native_raster_width(page)
+            .map(|width| width as f32 / (page.width().value / 72.0));
+        if let Some(native_dpi) = native_dpi
+            && render_dpi > native_dpi * 1.05
+        {
+            info!(
+                "Page {}: rendering at {:.0} dpi upscales the {:.0} dpi scan",
+                page_no, render_dpi, native_dpi
+            );
+        }
+        let effective_dpi = native_dpi.map_or(render_dpi, |native| native.min(render_dpi));
+        if effective_dpi < min_dpi {
+            confirm_large(
+                args,
+                format!(
+                    "Page {} has an effective resolution of {:.0} dpi, below \
+                    --min-effective-dpi {}",
+                    page_no, effective_dpi, min_dpi
+                ),
+            )?;
+        }
+    }
+
+    // render wider when cropping, so the kept region ends up at page_width
+    let mut render_width = match crop {
+        Some(crop) => page_width as f32 * depth_scale / (crop.right - crop.left),
+        None => page_width as f32 * depth_scale,
+    };
+    // with --dpi both sides follow from the page's size in points
+    let mut render_height = render_width * page.height().value / page.width().value;
+    // a narrow crop, or a tall page, would make a bitmap of several gigabytes
+    let scale = (MAX_RENDER_DIMENSION / render_width.max(render_height)).min(1.0);
+    if scale < 1.0 {
+        warn!(
+            "Page {}: a {}x{} render exceeds {} pixels, keeping {:.0}px of width instead of {}px",
+            page_no,
+            render_width.round(),
+            render_height.round(),
+            MAX_RENDER_DIMENSION,
+            page_width as f32 * scale,
+            page_width
+        );
+        render_width *= scale;
+        render_height *= scale;
+    }
+    let (render_width, render_height) = (render_width.round() as i32, render_height.round() as i32);
+    let mut config = PdfRenderConfig::new()
+        .set_target_width(render_width)
+        .render_form_data(args.render_forms);
+    if args.dpi.is_some() {
+        config = config.set_target_height(render_height);
+    }
+    let bitmap = page.render_with_config(&config)?;
+
+    // convert to rgba8
+    let width = bitmap.width() as u32;
+    let height = bitmap.height() as u32;
+    let image = bitmap.as_image();
+    let rgba = image.as_rgba8().unwrap();
+    let (width, height, rgba) = match crop {
+        Some(crop) => {
+            let (width, height, pixels) = render::crop_rgba(rgba.as_raw(), width, height, crop);
+            (width, height, Cow::Owned(pixels))
+        }
+        None => (width, height, Cow::Borrowed(rgba.as_raw().as_slice())),
+    };
+    if args.skip_blank {
+        let (mean, std_dev) = render::luminance_stats(&rgba);
+        if render::is_blank((mean, std_dev), args.blank_threshold) {
+            debug!(
+                "Page {}: blank, mean luminance {:.4}, standard deviation {:.4}",
+                page_no, mean, std_dev
+            );
+            return Ok(None);
+        }
+    }
+    let (width, height, pixels) = match args.bit_depth {
+        BitDepth::Eight => (width, height, rgba),
+        BitDepth::Sixteen => {
+            let (width, height, pixels) = render::downsample_to_16(&rgba, width, height);
+            (width, height, Cow::Owned(pixels))
+        }
+    };
+
+    if let Some(limit) = args.warn_large_pixels
+        && (width as u64) * (height as u64) > limit
+    {
+        confirm_large(
+            args,
+            format!(
+                "Page {} renders to {}x{} ({} pixels), above --warn-large-pixels {}",
+                page_no,
+                width,
+                height,
+                width as u64 * height as u64,
+                limit
+            ),
+        )?;
+    }
+
+    // text pages lose nothing in gray, and shrink to a quarter
+    let pixels = if args.grayscale {
+        Cow::Owned(render::grayscale(&pixels, args.bit_depth))
+    } else {
+        pixels
+    };
+
+    let buffer = match args.format {
+        ImageFormat::Png => encode_png(args, &pixels, width, height, page_no, crop)?,
+        ImageFormat::Jpeg => render::encode_jpeg(
+            &pixels,
+            width,
+            height,
+            args.quality.unwrap_or(args::DEFAULT_JPEG_QUALITY),
+            args.grayscale,
+        )?,
+        ImageFormat::Webp => render::encode_webp(&pixels, width, height, args.grayscale)?,
+    };
+
+    if let Some(limit) = args.warn_large_bytes
+        && buffer.len() as u64 > limit
+    {
+        confirm_large(
+            args,
+            format!(
+                "Page {} ({}x{}) encodes to {} bytes, above --warn-large-bytes {}",
+                page_no,
+                width,
+                height,
+                buffer.len(),
+                limit
+            ),
+        )?;
+    }
+
+    Ok(Some(buffer))
+}
+
+/// Encode the rendered pixels as PNG, RGBA or gray at the selected bit
+/// depth, with the render settings in text chunks under --embed-metadata.
+fn encode_png(
+    args: &Args,
+    pixels: &[u8],
+    width: u32,
+    height: u32,
+    page_no: usize,
+    crop: Option<render::Crop>,
+) -> Result<Vec<u8>> {
+    let mut buffer = Vec::new();
+    let mut encoder = png::Encoder::new(&mut buffer, width, height);
+    encoder.set_color(if args.grayscale {
+        png::ColorType::Grayscale
+    } else {
+        png::ColorType::Rgba
+    });
+    encoder.set_depth(match args.bit_depth {
+        BitDepth::Eight => png::BitDepth::Eight,
+        BitDepth::Sixteen => png::BitDepth::Sixteen,
+    });
+    if args.embed_metadata {
+        encoder.add_text_chunk(
+            "Software".to_string(),
+            format!("pdftopng-rs {}", env!("CARGO_PKG_VERSION")),
+        )?;
+        encoder.add_itxt_chunk(
+            "Comment".to_string(),
+            format!(
+                "page={}; size={}x{}; bit_depth={:?}; grayscale={}; \
+                requested_width={}; dpi={:?}; native_raster={}; \
+                crop={:?}; token_budget={:?}",
+                page_no,
+                width,
+                height,
+                args.bit_depth,
+                args.grayscale,
+                args.page_width.unwrap_or(args::DEFAULT_PAGE_WIDTH),
+                args.dpi,
+                args.native_raster,
+                crop,
+                args.prompt_budget()
+            ),
+        )?;
+    }
+
+    let mut writer = encoder.write_header()?;
+    writer.write_image_data(pixels)?;
+    writer.finish()?;
+
+    Ok(buffer)
+}
+
+/// The page's image, from the render cache when it has it.
+fn cached_render(
+    args: &Args,
+    prompt: &str,
+    page: &PdfPage,
+    page_no: usize,
+    width_scale: f32,
+    render_cache: Option<&cache::RenderCache>,
+) -> Result<Option<Vec<u8>>> {
+    let cache_path = render_cache.map(|cache| cache.path(page_no));
+    if let Some(buffer) = cache_path
+        .as_ref()
+        .and_then(|path| std::fs::read(path).ok())
+    {
+        debug!("Page {}: using cached render", page_no);
+        return Ok(Some(buffer));
+    }
+    let buffer = render_page(args, prompt, page, page_no, width_scale)?;
+    // blank pages are not cached, they render again to be found blank
+    if let Some(path) = &cache_path
+        && let Some(buffer) = &buffer
+    {
+        cache::store(path, buffer)?;
+    }
+    Ok(buffer)
+}
+
+/// Sampling options for every page request, and for --selftest so it
+/// answers the way a real run would.
+fn generate_options(args: &Args) -> GenerateOptions {
+    GenerateOptions {
+        temperature: Some(0.0),
+        top_p: None,
+        top_k: None,
+        num_predict: None,
+        seed: args.seed,
+        num_ctx: args.num_ctx,
+    }
+}
+
+/// Every setting that changes the bytes produced by `render_page`, used to
+/// key the render cache.
+fn render_params(args: &Args, prompt: &str) -> String {
+    let mut params = format!(
+        "{};width={};native_raster={};metadata={}",
+        args.format.extension(),
+        args.page_width.unwrap_or(args::DEFAULT_PAGE_WIDTH),
+        args.native_raster,
+        args.embed_metadata
+    );
+    if args.format == ImageFormat::Jpeg {
+        params += &format!(
+            ";quality={}",
+            args.quality.unwrap_or(args::DEFAULT_JPEG_QUALITY)
+        );
+    }
+    if let Some(dpi) = args.dpi {
+        params += &format!(";dpi={}", dpi);
+    }
+    if args.grayscale {
+        params += ";grayscale";
+    }
+    if args.crop_to_text {
+        params += &format!(";crop_to_text={}", args.crop_padding);
+    } else if args.crop_to_content {
+        params += &format!(";crop_to_content={}", args.crop_padding);
+    }
+    if args.render_forms {
+        params += ";forms";
+    }
+    if args.bit_depth == BitDepth::Sixteen {
+        params += ";bit_depth=16";
+    }
+    if let Some(budget) = args.prompt_budget() {
+        params += &format!(";budget={};prompt={}", budget, prompt);
+    }
+    // a page cached without the check may be blank
+    if args.skip_blank {
+        params += &format!(";skip_blank={}", args.blank_threshold);
+    }
+    params
+}
+
+/// Where page `page_no`'s output with extension `ext` goes, following
+/// --name-template. Directories the template adds are created.
+fn page_path(
+    args: &Args,
+    dir: &Path,
+    input_file: &str,
+    page_no: usize,
+    total: usize,
+    ext: &str,
+) -> Result<PathBuf> {
+    let name = naming::render_template(
+        &args.name_template,
+        &naming::Context {
+            stem: naming::stem(input_file),
+            page: page_no,
+            total,
+            ext,
+        },
+    )?;
+    let path = dir.join(name);
+    if let Some(parent) = path.parent() {
+        std::fs::create_dir_all(parent)?;
+    }
+    Ok(path)
+}
+
+/// File name --combine writes an input's transcription to, in the output
+/// directory: `template` with the input's name.
+fn combined_name(template: &str, input: &str) -> Result<String> {
+    let file_name = if download::is_url(input) {
+        download::file_name(input)
+    } else {
+        Path::new(input).file_name().map_or_else(
+            || input.to_string(),
+            |name| name.to_string_lossy().into_owned(),
+        )
+    };
+    naming::render_combined(template, naming::stem(&file_name))
+}
+
+/// Check that no two inputs would be combined into the same file.
+fn check_combined_names(template: &str, inputs: &[String]) -> Result<()> {
+    let mut names = HashMap::new();
+    for input in inputs {
+        let name = combined_name(template, input)?;
+        if let Some(other) = names.insert(name.clone(), input) {
+            return Err(anyhow::anyhow!(
+                "--combine would write both {} and {} to {}, rename one \
+                of them or put {{stem}} in --combine-name",
+                other,
+                input,
+                name
+            ));
+        }
+    }
+    Ok(())
+}
+
+/// Where a per-document output given as PATH goes: PATH itself for a
+/// single input, otherwise PATH with the input's name appended.
+fn per_document_path(args: &Args, path: &str, input_file: &str) -> PathBuf {
+    let path = Path::new(path);
+    if args.files.len() == 1 {
+        return path.to_path_buf();
+    }
+    path.with_file_name(format!(
+        "{}-{}.{}",
+        path.file_stem().unwrap_or_default().to_string_lossy(),
+        Path::new(input_file)
+            .file_stem()
+            .unwrap_or_default()
+            .to_string_lossy(),
+        path.extension().unwrap_or_default().to_string_lossy()
+    ))
+}
+
+/// Write the --json-out document of `source` with the pages transcribed so far.
+fn write_json_out(
+    args: &Args,
+    path: &str,
+    source: &str,
+    input_file: &str,
+    results: &DocumentResults,
+) -> Result<()> {
+    let path = per_document_path(args, path, input_file);
+    let document = serde_json::json!({
+        "file": source,
+        "pages": results
+            .texts
+            .iter()
+            .map(|(page_no, (text, tokens))| serde_json::json!({
+                "page": page_no,
+                "text": text,
+                "tokens": tokens,
+                "text_layer_similarity": results.similarities.get(page_no),
+            }))
+            .collect::<Vec<_>>(),
+    });
+    let json = if args.json_pretty {
+        serde_json::to_string_pretty(&document)?
+    } else {
+        serde_json::to_string(&document)?
+    };
+    output::write_atomic(&path, json)?;
+    Ok(())
+}
+
+/// Write failures.json to the output directory: the file, page and error of
+/// every page that failed so far.
+fn write_failures(args: &Args, stats: &RunStats) -> Result<PathBuf> {
+    let failures = stats
+        .failures
+        .iter()
+        .map(
+            |(file, page, error)| serde_json::json!({ "file": file, "page": page, "error": error }),
+        )
+        .collect::<Vec<_>>();
+    let path = Path::new(&args.output_dir).join("failures.json");
+    output::write_atomic(&path, serde_json::to_string_pretty(&failures)?)?;
+    Ok(path)
+}
+
+/// Write accuracy.json to the output directory: the CER and WER of each page
+/// scored so far, and their means, which are returned.
+fn write_accuracy(args: &Args, accuracy: &[(String, usize, eval::Scores)]) -> Result<(f64, f64)> {
+    let mean = |score: fn(&eval::Scores) -> f64| {
+        accuracy
+            .iter()
+            .map(|(_, _, scores)| score(scores))
+            .sum::<f64>()
+            / accuracy.len() as f64
+    };
+    let (mean_cer, mean_wer) = (mean(|scores| scores.cer), mean(|scores| scores.wer));
+    let report = serde_json::json!({
+        "pages": accuracy
+            .iter()
+            .map(|(file, page, scores)| serde_json::json!({
+                "file": file,
+                "page": page,
+                "cer": scores.cer,
+                "wer": scores.wer,
+            }))
+            .collect::<Vec<_>>(),
+        "mean_cer": mean_cer,
+        "mean_wer": mean_wer,
+    });
+    let path = Path::new(&args.output_dir).join("accuracy.json");
+    output::write_atomic(&path, serde_json::to_string_pretty(&report)?)?;
+    Ok((mean_cer, mean_wer))
+}
+
+/// Rewrite the reports with what the run has so far, for
+/// --flush-interval-secs: the --json-out document being transcribed, the
+/// failures and the accuracy of the documents already scored.
+fn flush_reports(
+    args: &Args,
+    source: &str,
+    input_file: &str,
+    results: &DocumentResults,
+    stats: &RunStats,
+    accuracy: &[(String, usize, eval::Scores)],
+) -> Result<()> {
+    if let Some(path) = &args.json_out {
+        write_json_out(args, path, source, input_file, results)?;
+    }
+    if !stats.failures.is_empty() {
+        write_failures(args, stats)?;
+    }
+    if args.ground_truth.is_some() && !accuracy.is_empty() {
+        write_accuracy(args, accuracy)?;
+    }
+    debug!("Reports flushed");
+    Ok(())
+}
+
+/// Run a page task, writing its error to `error_path` if it fails, and
+/// removing one left there by an earlier run if it succeeds.
+async fn with_error_file(
+    error_path: Option<PathBuf>,
+    task: impl Future<Output = Result<PageOutcome>>,
+) -> Result<PageOutcome> {
+    let outcome = task.await;
+    if let Some(error_path) = &error_path {
+        match &outcome {
+            Ok(_) => {
+                let _ = std::fs::remove_file(error_path);
+            }
+            Err(err) => {
+                if let Err(write_err) = std::fs::write(error_path, format!("{:#}\n", err)) {
+                    warn!("Cannot write {:?}: {}", error_path, write_err);
+                }
+            }
+        }
+    }
+    outcome
+}
+
+/// Account for a finished page task in the progress bar, the run stats and
+/// the checkpoint.
+fn record_page(
+    result: Result<(TaskId, Result<PageOutcome>), JoinError>,
+    source: &str,
+    stats: &mut RunStats,
+    checkpoint: &mut Option<Checkpoint>,
+    results: &mut DocumentResults,
+    metrics: &Metrics,
+) -> Result<()> {
+    if !SUMMARY_ONLY.load(Ordering::Relaxed) {
+        inc_progress_bar();
+    }
+    stats.pages += 1;
+    let (task, result) = match result {
+        Ok((task, result)) => (task, result),
+        Err(err) => (err.id(), Err(err.into())),
+    };
+    match result {
+        Ok(outcome) => {
+            if let Some(checkpoint) = checkpoint {
+                checkpoint.mark_done(source, outcome.page_no)?;
+            }
+            stats.tokens += outcome.tokens;
+            if let Some(limit) = outcome.truncated {
+                stats.truncated += 1;
+                results.truncated_pages.insert(outcome.page_no, limit);
+            }
+            if outcome.refused {
+                stats.refused.push((source.to_string(), outcome.page_no));
+            }
+            if let Some(num_ctx) = outcome.grown_ctx {
+                stats
+                    .grown_ctx
+                    .push((source.to_string(), outcome.page_no, num_ctx));
+            }
+            if let Some(similarity) = outcome.similarity {
+                results.similarities.insert(outcome.page_no, similarity);
+            }
+            if let Some(text) = outcome.text {
+                results
+                    .texts
+                    .insert(outcome.page_no, (text, outcome.tokens));
+            }
+            if outcome.empty {
+                results.retry_pages.insert(outcome.page_no);
+            }
+            results.models.insert(outcome.page_no, outcome.model);
+        }
+        Err(err) => {
+            let page_no = results.task_pages.get(&task).copied().unwrap_or_default();
+            results.retry_pages.insert(page_no);
+            error!("Page {} failed: {:#}", page_no, err);
+            stats
+                .failures
+                .push((source.to_string(), page_no, format!("{:#}", err)));
+            stats.failed += 1;
+            metrics.pages_failed.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+    Ok(())
+}
+
+async fn run(args: Args) -> Result<RunStats> {
+    let mut stats = RunStats::default();
+    SUMMARY_ONLY.store(args.summary_only, Ordering::Relaxed);
+
+    let passwords = match &args.password_file {
+        Some(file) => {
+            let passwords = std::fs::read_to_string(file)
+                .map_err(|err| anyhow::anyhow!("Cannot read password file {:?}: {}", file, err))?;
+            // the passwords themselves stay out of the error
+            serde_json::from_str::<HashMap<String, String>>(&passwords).map_err(|err| {
+                anyhow::anyhow!(
+                    "Password file {:?} must be a JSON object of file names to passwords (line {})",
+                    file,
+                    err.line()
+                )
+            })?
+        }
+        None => HashMap::new(),
+    };
+    let format_schema = match &args.format_schema {
+        Some(file) => {
+            let schema = std::fs::read_to_string(file)
+                .map_err(|err| anyhow::anyhow!("Cannot read format schema {:?}: {}", file, err))?;
+            let schema = serde_json::from_str::<serde_json::Value>(&schema).map_err(|err| {
+                anyhow::anyhow!("Format schema {:?} is not valid JSON: {}", file, err)
+            })?;
+            // Ollama would answer 400 to every page
+            if !schema.is_object() {
+                return Err(anyhow::anyhow!(
+                    "Format schema {:?} must be a JSON object, like {{\"type\": \"object\", ...}}",
+                    file
+                ));
+            }
+            Some(schema)
+        }
+        None => None,
+    };
+    output::check(&args.output_format, format_schema.is_some())?;
+    let retry = RetryPolicy {
+        max_retries: args.max_retries,
+        base_delay: Duration::from_millis(args.retry_base_delay),
+    };
+    // failures surface to the page task, which moves on to the next server
+    let switch_nodes = args.retry_different_node && args.ollama_url.len() > 1;
+    let client_retry = if switch_nodes {
+        RetryPolicy {
+            max_retries: 0,
+            ..retry
+        }
+    } else {
+        retry
+    };
     let ollamas = args
         .ollama_url
         .iter()
         .map(|url| {
             let (url, count) = url.split_once('@').unwrap_or((url, "1"));
             let count = count.parse::<usize>().unwrap_or(1);
-            println!("Creating {} ollamas from {:?}", count, url);
-            OllamaClient::new(url, &args.model, count)
+            status!("Creating {} ollamas from {:?}", count, url);
+            let client = OllamaClient::new(url, &args.model, count).with_timeouts(
+                args.connect_timeout.map(Duration::from_secs),
+                args.request_timeout.map(Duration::from_secs),
+                (args.timeout_secs > 0).then(|| Duration::from_secs(args.timeout_secs)),
+            )?;
+            Ok(client
+                .with_retries(client_retry)
+                .with_system_prompt(args.system_prompt.clone())
+                .with_api_key(args.api_key.clone())
+                .with_format(format_schema.clone()))
         })
-        .collect::<Vec<_>>();
+        .collect::<Result<Vec<_>>>()?;
 
     if args.enum_models && !args.ollama_url.is_empty() {
         for ollama in ollamas {
@@ -98,188 +1022,1693 @@ async fn main() -> Result<()> {
                 );
             }
         }
-        return Ok(());
+        return Ok(stats);
     }
 
     let mut ollama_list = Vec::new();
     for ollama in &ollamas {
         let ollama_url = ollama.url().to_string();
         let ollama_count = ollama.count();
-        println!("Adding {} ollamas from {:?}", ollama_count, ollama_url);
+        status!("Adding {} ollamas from {:?}", ollama_count, ollama_url);
         for _ in 0..ollama_count {
             ollama_list.push(ollama);
         }
     }
 
-    let pdfium = Pdfium::default();
+    // pages are rendered ahead, requests wait here for a free worker
+    let concurrency = args.concurrency.unwrap_or(ollama_list.len()).max(1);
+    let request_slots = Arc::new(tokio::sync::Semaphore::new(concurrency));
+
+    let prompt = args.resolve_prompt()?;
+    naming::validate(&args.name_template)?;
+    let page_selection = args
+        .pages
+        .as_deref()
+        .map(pages::parse_page_spec)
+        .transpose()?;
+    if let Some(dpi) = args.dpi
+        && dpi <= 0.0
+    {
+        return Err(anyhow::anyhow!("--dpi must be positive, got {}", dpi));
+    }
+    if !(0.0..=1.0).contains(&args.blank_threshold) {
+        return Err(anyhow::anyhow!(
+            "--blank-threshold must be between 0.0 and 1.0, got {}",
+            args.blank_threshold
+        ));
+    }
+
+    if let Some(budget) = args.prompt_token_budget
+        && let Some(num_ctx) = args.num_ctx
+        && budget >= num_ctx as usize
+    {
+        return Err(anyhow::anyhow!(
+            "--prompt-token-budget {} leaves no room for the answer in --num-ctx {}",
+            budget,
+            num_ctx
+        ));
+    }
+
+    if args.dpi.is_some() && args.page_width.is_some() {
+        warn!("Both --dpi and --page-width given, rendering at --dpi");
+    }
+    if args.format != ImageFormat::Jpeg && args.quality.is_some() {
+        warn!("--quality only applies with --format jpeg, ignored");
+    }
+    if args.format != ImageFormat::Png {
+        if args.bit_depth == BitDepth::Sixteen {
+            return Err(anyhow::anyhow!("--bit-depth 16 needs --format png"));
+        }
+        if args.split_columns {
+            return Err(anyhow::anyhow!("--split-columns needs --format png"));
+        }
+        if args.embed_metadata {
+            warn!("--embed-metadata only applies to PNG images, ignored");
+        }
+    }
+
+    let lang_prompts = lang::parse_mapping(&args.lang_prompt, "--lang-prompt")?
+        .into_iter()
+        .map(|(lang, file)| {
+            std::fs::read_to_string(&file)
+                .map(|prompt| (lang.clone(), prompt))
+                .map_err(|err| {
+                    anyhow::anyhow!("Cannot read prompt file {:?} for {}: {}", file, lang, err)
+                })
+        })
+        .collect::<Result<BTreeMap<_, _>>>()?;
+    let lang_models = lang::parse_mapping(&args.lang_model, "--lang-model")?;
+    let detect_language =
+        args.detect_language || !lang_prompts.is_empty() || !lang_models.is_empty();
+
+    let cleanup_prompt = match &args.cleanup_prompt {
+        Some(file) => Some(
+            std::fs::read_to_string(file)
+                .map_err(|err| anyhow::anyhow!("Cannot read cleanup prompt {:?}: {}", file, err))?,
+        ),
+        None => None,
+    };
+
+    let boilerplate = if !args.strip_boilerplate {
+        None
+    } else if args.boilerplate_phrase.is_empty() {
+        Some(
+            postprocess::DEFAULT_BOILERPLATE
+                .iter()
+                .map(|phrase| phrase.to_string())
+                .collect::<Vec<_>>(),
+        )
+    } else {
+        Some(args.boilerplate_phrase.clone())
+    };
+
+    let refusal_patterns = if args.refusal_patterns.is_empty() {
+        postprocess::DEFAULT_REFUSAL_PATTERNS
+            .iter()
+            .map(|pattern| pattern.to_string())
+            .collect::<Vec<_>>()
+    } else {
+        args.refusal_patterns.clone()
+    };
+
+    let ensemble_prompts = args
+        .ensemble_prompts
+        .iter()
+        .map(|file| {
+            std::fs::read_to_string(file)
+                .map_err(|err| anyhow::anyhow!("Cannot read ensemble prompt {:?}: {}", file, err))
+        })
+        .collect::<Result<Vec<_>>>()?;
+
+    let pdfium = render::bind_pdfium(args.pdfium_path.as_deref())?;
+
+    if args.command == Some(Command::Selftest) {
+        let ok = selftest::run(&args, &prompt, &pdfium, &ollamas[0]).await?;
+        stats.pages = 1;
+        stats.failed = usize::from(!ok);
+        return Ok(stats);
+    }
+
+    // --metadata-only reads the documents and leaves no file or listener behind
+    let metrics = Arc::new(Metrics::default());
+    if let Some(addr) = &args.metrics_addr
+        && !args.metadata_only
+    {
+        metrics::serve(addr, metrics.clone()).await?;
+    }
+
+    let mut checkpoint = match &args.checkpoint {
+        Some(path) if !args.metadata_only => Some(Checkpoint::open(
+            path,
+            args.resume_checkpoint,
+            args.checkpoint_every.max(1),
+            Duration::from_secs(args.checkpoint_interval),
+        )?),
+        _ => None,
+    };
+
+    let token_timing = match &args.token_timing {
+        Some(path) if !args.metadata_only => {
+            let mut file = std::fs::File::create(path)?;
+            writeln!(file, "file,page,backend,output,chunk,elapsed_ms,bytes")?;
+            Some(Arc::new(Mutex::new(file)))
+        }
+        _ => None,
+    };
 
     let start = Instant::now();
-    for input_pdf in args.files {
+    let mut last_dispatch: Option<Instant> = None;
+    let mut outlines = Vec::new();
+    let mut accuracy = Vec::new();
+    let flush_interval = args.flush_interval_secs.map(Duration::from_secs);
+    let mut last_flush = Instant::now();
+    let mut failures_flushed = false;
+    let too_many_failures =
+        |stats: &RunStats| args.max_failures.is_some_and(|max| stats.failed >= max);
+    let progress_reporter = (args.progress_json && !args.metadata_only).then(|| {
+        metrics::report_progress(
+            metrics.clone(),
+            Duration::from_secs(args.progress_interval.max(1)),
+        )
+    });
+
+    if args.combine {
+        check_combined_names(&args.combine_name, &args.files)?;
+    }
+
+    // one connection pool for every downloaded input
+    let download_client = reqwest::Client::new();
+    for input_pdf in &args.files {
+        stats.files += 1;
+        // pages are recorded under the name given on the command line
+        let source = input_pdf.as_str();
+        let download = if download::is_url(input_pdf) {
+            status!("Downloading {}", input_pdf);
+            Some(download::download_pdf(&download_client, input_pdf).await?)
+        } else {
+            None
+        };
+        let input_pdf = match &download {
+            Some(download) => download.path().to_string_lossy().into_owned(),
+            None => input_pdf.clone(),
+        };
+
         let input_file = Path::new(&input_pdf).file_name().unwrap().to_str().unwrap();
-        println!("Loading {}", input_file);
+        status!("Loading {}", input_file);
 
-        let document = pdfium.load_pdf_from_file(&input_pdf, None)?;
+        let password = passwords
+            .get(source)
+            .or_else(|| passwords.get(input_file))
+            .or(args.password.as_ref());
+        let document = match pdfium.load_pdf_from_file(&input_pdf, password.map(String::as_str)) {
+            Ok(document) => document,
+            Err(PdfiumError::PdfiumLibraryInternalError(PdfiumInternalError::PasswordError)) => {
+                return Err(anyhow::anyhow!(match password {
+                    Some(_) => format!("Wrong password for {}", input_file),
+                    None => format!(
+                        "{} is password protected, pass its password with \
+                        --password or --password-file",
+                        input_file
+                    ),
+                }));
+            }
+            Err(err) => return Err(err.into()),
+        };
         if args.verbose {
-            println!("Document {:?} chargé en {:?}", input_pdf, start.elapsed());
+            status!("Document {:?} chargé en {:?}", input_pdf, start.elapsed());
         }
 
-        let page_count = document.pages().len();
-        let page_start = args.page_start.unwrap_or(1);
-        if page_start == 0 {
-            return Err(anyhow::anyhow!("Page start cannot be 0"));
+        let document_metadata = metadata::extract(&document);
+        if args.metadata_only {
+            println!("{}:", source);
+            if document_metadata.is_empty() {
+                println!("  (no metadata)");
+            }
+            for (name, value) in &document_metadata {
+                println!("  {}: {}", name, value);
+            }
+            continue;
         }
-        let page_end = args.page_end.unwrap_or(page_count as usize);
-        if page_end < page_start {
-            return Err(anyhow::anyhow!("Page end cannot be less than page start"));
+
+        if args.render_forms {
+            match document.form().map(|form| form.form_type()) {
+                None => info!("{} has no form, rendering pages as usual", input_file),
+                Some(PdfFormType::XfaFull) => warn!(
+                    "{} is an XFA form: pdfium only draws its static fallback content",
+                    input_file
+                ),
+                Some(form_type) => info!("{} has a {:?} form", input_file, form_type),
+            }
         }
-        if page_end > page_count as usize {
-            return Err(anyhow::anyhow!(
-                "Page end cannot be greater than page count"
-            ));
+
+        if args.dump_outline.is_some() {
+            let outline = outline::outline_markdown(&document)
+                .unwrap_or_else(|| format!("_No outline in {}._\n", input_file));
+            outlines.push((input_file.to_string(), outline));
         }
 
-        init_progress_bar(page_end - page_start + 1);
+        let page_count = document.pages().len();
+        let selected: Vec<usize> = match &page_selection {
+            Some(selection) => {
+                let beyond = selection.beyond(page_count as usize);
+                if !beyond.is_empty() {
+                    return Err(anyhow::anyhow!(
+                        "--pages {} goes past the last page: {} has {} pages",
+                        beyond.join(","),
+                        input_file,
+                        page_count
+                    ));
+                }
+                selection.pages(page_count as usize).into_iter().collect()
+            }
+            None => {
+                let page_start = args.page_start.unwrap_or(1);
+                if page_start == 0 {
+                    return Err(anyhow::anyhow!("Page start cannot be 0"));
+                }
+                let page_end = args.page_end.unwrap_or(page_count as usize);
+                if page_end < page_start {
+                    return Err(anyhow::anyhow!("Page end cannot be less than page start"));
+                }
+                if page_end > page_count as usize {
+                    return Err(anyhow::anyhow!(
+                        "Page end cannot be greater than page count"
+                    ));
+                }
+                (page_start..=page_end).collect()
+            }
+        };
+
+        if !args.summary_only {
+            init_progress_bar(selected.len());
+        }
+        metrics
+            .pages_planned
+            .fetch_add(selected.len(), Ordering::Relaxed);
+
+        // relative to the current directory unless absolute, parents included
+        let dir_path = Path::new(&args.output_dir);
+        std::fs::create_dir_all(dir_path).map_err(|err| {
+            anyhow::anyhow!("Cannot create output directory {:?}: {}", dir_path, err)
+        })?;
 
-        let dir_path = Path::new("output");
-        std::fs::create_dir_all(dir_path).unwrap();
+        let render_cache = match &args.render_cache_dir {
+            Some(dir) => Some(cache::RenderCache::new(
+                Path::new(dir),
+                Path::new(&input_pdf),
+                &render_params(&args, &prompt),
+            )?),
+            None => None,
+        };
 
-        let mut pages_to_remove = Vec::new();
-        let mut handles = Vec::new();
+        let mut handles = JoinSet::new();
+        let mut results = DocumentResults::default();
 
         let start = Instant::now();
         let pages = document.pages();
-        for (page_no, page) in pages.iter().enumerate() {
-            let page_no = page_no + 1;
-            if page_no < page_start {
-                continue;
-            }
-            if page_no > page_end {
-                break;
+        let page_order = pages::ordered(pages, selected, args.page_order);
+
+        // pages found blank by --skip-blank while prerendering
+        let mut blank_pages = BTreeSet::new();
+        // with --prerender, every page is on disk before the first request
+        if args.prerender {
+            for &page_no in &page_order {
+                if let Some(checkpoint) = &checkpoint
+                    && checkpoint.is_done(source, page_no)
+                {
+                    continue;
+                }
+                let page = pages.get((page_no - 1) as PdfPageIndex)?;
+                let Some(buffer) =
+                    cached_render(&args, &prompt, &page, page_no, 1.0, render_cache.as_ref())?
+                else {
+                    blank_pages.insert(page_no);
+                    continue;
+                };
+                let image_path = page_path(
+                    &args,
+                    dir_path,
+                    input_file,
+                    page_no,
+                    page_count as usize,
+                    args.format.extension(),
+                )?;
+                std::fs::write(&image_path, &buffer)?;
             }
+            status!("{} rendered in {:?}", input_file, start.elapsed());
+        }
+        let inference_start = Instant::now();
 
-            set_progress_bar_action("processing", Color::Green, Style::Bold);
+        // --retry-escalate-width sends the failed and blank answers through
+        // again, each pass rendering wider than the last
+        let mut pass_pages = page_order;
+        let mut escalation = 0;
+        loop {
+            let width_scale = ESCALATION_STEP.powi(escalation);
+            for page_no in pass_pages {
+                let skip_blank = |stats: &mut RunStats| {
+                    status!(" - page {}: blank, skipping", page_no);
+                    stats.blank.push((source.to_string(), page_no));
+                    metrics.pages_planned.fetch_sub(1, Ordering::Relaxed);
+                    if !args.summary_only {
+                        inc_progress_bar();
+                    }
+                };
+                if blank_pages.contains(&page_no) {
+                    skip_blank(&mut stats);
+                    continue;
+                }
+                if escalation == 0
+                    && let Some(checkpoint) = &checkpoint
+                    && checkpoint.is_done(source, page_no)
+                {
+                    status!(" - page {}: done in checkpoint, skipping", page_no);
+                    metrics.pages_planned.fetch_sub(1, Ordering::Relaxed);
+                    if !args.summary_only {
+                        inc_progress_bar();
+                    }
+                    continue;
+                }
 
-            if args.show_content {
-                for object in page.objects().iter() {
-                    if let Some(text_object) = object.as_text_object() {
-                        let h = text_object.get_horizontal_translation();
-                        let v = text_object.get_vertical_translation();
-                        println!(
-                            "Content: {:?} [{:?},{:?}]",
-                            text_object.text(),
-                            h.to_mm(),
-                            v.to_mm()
-                        );
+                if escalation == 0 && args.resume && !args.figures_only {
+                    let content_path = page_path(
+                        &args,
+                        dir_path,
+                        input_file,
+                        page_no,
+                        page_count as usize,
+                        "md",
+                    )?;
+                    let done = args.output_format.iter().all(|format| {
+                        std::fs::metadata(content_path.with_extension(format.extension()))
+                            .is_ok_and(|metadata| metadata.len() > 0)
+                    });
+                    if done {
+                        status!(" - page {}: already transcribed, skipping", page_no);
+                        // the whole-document files still need the page
+                        if (args.merge.is_some() || args.combine)
+                            && let Ok(text) = std::fs::read_to_string(&content_path)
+                        {
+                            results.texts.insert(page_no, (text, 0));
+                        }
+                        metrics.pages_planned.fetch_sub(1, Ordering::Relaxed);
+                        if !args.summary_only {
+                            inc_progress_bar();
+                        }
+                        continue;
                     }
                 }
-            }
 
-            let bitmap = page.render_with_config(
-                &PdfRenderConfig::new().set_target_width(args.page_width.into()),
-            )?;
+                // only load the selected pages, pdfium reads the file on demand
+                let page = pages.get((page_no - 1) as PdfPageIndex)?;
 
-            // convert to rgba8
-            let width = bitmap.width() as u32;
-            let height = bitmap.height() as u32;
-            let image = bitmap.as_image();
-            let rgba = image.as_rgba8().unwrap();
+                if let Some(min_free) = args.min_free_disk {
+                    disk::ensure_free(dir_path, min_free, args.abort_on_low_disk).await?;
+                }
 
-            let base_input_pdf = Path::new(&input_pdf).file_name().unwrap().to_str().unwrap();
+                if !args.summary_only {
+                    set_progress_bar_action("processing", Color::Green, Style::Bold);
+                }
 
-            // write to png
-            let page_path =
-                base_input_pdf.replace(".pdf", format!("-page-{:06}.png", page_no).as_str());
-            let image_path = dir_path.join(page_path);
+                if args.show_content {
+                    for object in page.objects().iter() {
+                        if let Some(text_object) = object.as_text_object() {
+                            let h = text_object.get_horizontal_translation();
+                            let v = text_object.get_vertical_translation();
+                            println!(
+                                "Content: {:?} [{:?},{:?}]",
+                                text_object.text(),
+                                h.to_mm(),
+                                v.to_mm()
+                            );
+                        }
+                    }
+                }
 
-            // write to memory buffer first
-            let mut buffer = Vec::new();
-            let mut encoder = png::Encoder::new(&mut buffer, width, height);
-            encoder.set_color(png::ColorType::Rgba);
-            encoder.set_depth(png::BitDepth::Eight);
+                if args.layout_json {
+                    let mut entries = Vec::new();
+                    for object in page.objects().iter() {
+                        if let Some(text_object) = object.as_text_object() {
+                            let width = text_object.width().map(|w| w.to_mm()).unwrap_or_default();
+                            let height =
+                                text_object.height().map(|h| h.to_mm()).unwrap_or_default();
+                            entries.push(serde_json::json!({
+                                "text": text_object.text(),
+                                "x_mm": text_object.get_horizontal_translation().to_mm(),
+                                "y_mm": text_object.get_vertical_translation().to_mm(),
+                                "width_mm": width,
+                                "height_mm": height,
+                                "font_size": text_object.scaled_font_size().value,
+                            }));
+                        }
+                    }
 
-            let mut writer = encoder.write_header().unwrap();
-            writer.write_image_data(&rgba).unwrap();
-            writer.finish().unwrap();
+                    std::fs::write(
+                        page_path(
+                            &args,
+                            dir_path,
+                            input_file,
+                            page_no,
+                            page_count as usize,
+                            "layout.json",
+                        )?,
+                        serde_json::to_string_pretty(&entries)?,
+                    )?;
+                }
 
-            // write buffer to file
-            std::fs::write(&image_path, &buffer).unwrap();
+                let language = if detect_language {
+                    let language = lang::detect(&lang::page_text(&page));
+                    debug!("Page {}: language {:?}", page_no, language);
+                    *stats
+                        .languages
+                        .entry(language.unwrap_or("unknown").to_string())
+                        .or_default() += 1;
+                    language
+                } else {
+                    None
+                };
+                // uncertain pages keep the global prompt and model
+                let page_base_prompt = language
+                    .and_then(|language| lang_prompts.get(language))
+                    .unwrap_or(&prompt);
 
-            // encode to base64
-            let base64 = base64::engine::general_purpose::STANDARD.encode(&buffer);
+                let mut page_images = Vec::new();
+                let mut ensemble = Vec::new();
 
-            let chat_message = ChatMessage {
-                role: Role::User,
-                content: args.prompt.clone(),
-                thinking: None,
-                images: Some(vec![base64]),
-            };
-            let messages = vec![chat_message];
+                // each output file gets the concatenated answers to its conversations
+                let outputs: Vec<(PathBuf, Vec<Vec<ChatMessage>>)> = if args.figures_only {
+                    let figures = render::figure_images(&page);
+                    if figures.is_empty() {
+                        status!(" - page {}: no figures", page_no);
+                        metrics.pages_planned.fetch_sub(1, Ordering::Relaxed);
+                        if !args.summary_only {
+                            inc_progress_bar();
+                        }
+                        continue;
+                    }
 
-            let options = GenerateOptions {
-                temperature: Some(0.0),
-                top_p: None,
-                top_k: None,
-                num_predict: None,
-            };
+                    let mut outputs = Vec::new();
+                    for (fig_no, figure) in figures.iter().enumerate() {
+                        let fig_path = |ext| {
+                            anyhow::Ok(naming::with_suffix(
+                                &page_path(
+                                    &args,
+                                    dir_path,
+                                    input_file,
+                                    page_no,
+                                    page_count as usize,
+                                    ext,
+                                )?,
+                                &format!("-fig-{:02}", fig_no + 1),
+                            ))
+                        };
+                        let image_path = fig_path("png")?;
+                        std::fs::write(&image_path, figure)?;
+                        page_images.push(image_path);
+
+                        let base64 = base64::engine::general_purpose::STANDARD.encode(figure);
+                        let messages = vec![ChatMessage {
+                            role: Role::User,
+                            content: args.figure_prompt.clone(),
+                            thinking: None,
+                            images: Some(vec![base64]),
+                        }];
+                        outputs.push((fig_path("md")?, vec![messages]));
+                    }
+                    outputs
+                } else {
+                    // write the page image
+                    let image_path = page_path(
+                        &args,
+                        dir_path,
+                        input_file,
+                        page_no,
+                        page_count as usize,
+                        args.format.extension(),
+                    )?;
+
+                    // a kept image from an interrupted run is as good as a new render
+                    let reuse_image = args.resume && args.keep && image_path.exists();
+                    let buffer = if escalation == 0 && (args.prerender || reuse_image) {
+                        let buffer = std::fs::read(&image_path)?;
+                        // an image kept by a run without --skip-blank was never checked
+                        if reuse_image
+                            && !args.prerender
+                            && args.skip_blank
+                            && render::is_blank(
+                                render::luminance_stats(&render::decode_rgba(&buffer)?),
+                                args.blank_threshold,
+                            )
+                        {
+                            skip_blank(&mut stats);
+                            continue;
+                        }
+                        buffer
+                    } else {
+                        // a wider retry must not come from, or land in, the cache
+                        let render_cache = render_cache.as_ref().filter(|_| escalation == 0);
+                        let Some(buffer) = cached_render(
+                            &args,
+                            &prompt,
+                            &page,
+                            page_no,
+                            width_scale,
+                            render_cache,
+                        )?
+                        else {
+                            skip_blank(&mut stats);
+                            continue;
+                        };
+                        // write buffer to file
+                        std::fs::write(&image_path, &buffer)?;
+                        buffer
+                    };
+                    // models take 8-bit images, the 16-bit render is for the archive
+                    let buffer = match args.bit_depth {
+                        BitDepth::Eight => buffer,
+                        BitDepth::Sixteen => render::eight_bit_png(&buffer)?,
+                    };
+
+                    let column_bounds = args
+                        .columns
+                        .and_then(|columns| columns::boundaries(columns, &page))
+                        .map(|bounds| match page_crop(&args, &page) {
+                            // boundaries are page fractions, the image may be cropped
+                            Some(crop) => columns::within(&bounds, crop.left, crop.right),
+                            None => bounds,
+                        })
+                        .filter(|bounds| bounds.len() > 2);
+                    let (hint, images) = match &column_bounds {
+                        Some(bounds) if args.split_columns => {
+                            debug!(
+                                "Page {}: splitting into {} columns",
+                                page_no,
+                                bounds.len() - 1
+                            );
+                            (String::new(), columns::split_png(&buffer, bounds)?)
+                        }
+                        Some(bounds) => (columns::prompt_hint(bounds.len() - 1), vec![buffer]),
+                        None => (String::new(), vec![buffer]),
+                    };
+
+                    // context the images take, one per conversation
+                    let image_tokens = images
+                        .iter()
+                        .map(|image| {
+                            render::image_size(image).map_or(0, |(width, height)| {
+                                budget::estimate_image_tokens(width as usize, height as usize)
+                            })
+                        })
+                        .collect::<Vec<_>>();
 
-            let ollama = &ollama_list[(page_no - 1) % ollama_list.len()];
-            let ollama_url = ollama.url().to_string();
-
-            println!("Sending request to Ollama {:?}", ollama_url);
-            let mut stream = ollama.generate_stream(&messages, &options);
-            let content_name =
-                base_input_pdf.replace(".pdf", format!("-page-{:06}.md", page_no).as_str());
-            let content_path = dir_path.join(content_name);
-
-            let handle = tokio::spawn(async move {
-                let mut token_count = 0;
-                let mut accumulated_response = String::new();
-                let mut start = None;
-                while let Some(response) = stream.try_next().await.unwrap() {
-                    if start.is_none() {
-                        start = Some(Instant::now());
+                    // encode to base64
+                    let images = images
+                        .iter()
+                        .map(|image| base64::engine::general_purpose::STANDARD.encode(image))
+                        .collect::<Vec<_>>();
+                    // one request per image, transcribed in order
+                    let conversations_for = |prompt: &str| {
+                        images
+                            .iter()
+                            .map(|base64| {
+                                let image = Some(vec![base64.clone()]);
+                                if !args.stable_prefix {
+                                    return vec![ChatMessage {
+                                        role: Role::User,
+                                        content: format!("{}{}", prompt, hint),
+                                        thinking: None,
+                                        images: image,
+                                    }];
+                                }
+                                // the same system message on every page, per-page
+                                // parts after it
+                                vec![
+                                    ChatMessage {
+                                        role: Role::System,
+                                        content: prompt.to_string(),
+                                        thinking: None,
+                                        images: None,
+                                    },
+                                    ChatMessage {
+                                        role: Role::User,
+                                        content: hint.trim_start().to_string(),
+                                        thinking: None,
+                                        images: image,
+                                    },
+                                ]
+                            })
+                            .collect::<Vec<_>>()
+                    };
+                    // with --ensemble-prompts, the first prompt gives the streamed
+                    // answer and the others are sent at the same time
+                    let conversations = match ensemble_prompts.split_first() {
+                        Some((first, others)) => {
+                            ensemble = others
+                                .iter()
+                                .map(|prompt| conversations_for(prompt))
+                                .collect();
+                            conversations_for(first)
+                        }
+                        None => conversations_for(page_base_prompt),
+                    };
+
+                    if let Some(num_ctx) = args.num_ctx {
+                        // the messages as sent, system prompt included; a lower
+                        // bound, the answer needs room too
+                        let system_tokens = args
+                            .system_prompt
+                            .as_deref()
+                            .map_or(0, budget::estimate_text_tokens);
+                        let needed = std::iter::once(&conversations)
+                            .chain(&ensemble)
+                            .flat_map(|conversations| conversations.iter().zip(&image_tokens))
+                            .map(|(messages, image_tokens)| {
+                                system_tokens
+                                    + image_tokens
+                                    + messages
+                                        .iter()
+                                        .map(|message| {
+                                            budget::estimate_text_tokens(&message.content)
+                                        })
+                                        .sum::<usize>()
+                            })
+                            .max()
+                            .unwrap_or_default();
+                        if needed > num_ctx as usize {
+                            warn!(
+                                "Page {}: the image and prompt take about {} tokens, \
+                                more than --num-ctx {}, expect truncated answers",
+                                page_no, needed, num_ctx
+                            );
+                        }
                     }
-                    trace!("Response: {:?}", response);
-                    debug!(
-                        "Processing response: done={}, text={}",
-                        response.done, response.message.content
+
+                    let content_path = page_path(
+                        &args,
+                        dir_path,
+                        input_file,
+                        page_no,
+                        page_count as usize,
+                        "md",
+                    )?;
+                    page_images.push(image_path);
+                    vec![(content_path, conversations)]
+                };
+
+                let options = generate_options(&args);
+
+                let mut ollama = ollama_list[(page_no - 1) % ollama_list.len()].clone();
+                if let Some(model) = language.and_then(|language| lang_models.get(language)) {
+                    debug!("Page {}: using model {}", page_no, model);
+                    ollama = ollama.with_model(model);
+                }
+                let mut ollama_url = ollama.url().to_string();
+                let model = ollama.model().to_string();
+                // every server, in order, to retry on the one after the failed one
+                let nodes = if switch_nodes {
+                    ollamas
+                        .iter()
+                        .map(|node| node.with_model(&model))
+                        .collect::<Vec<_>>()
+                } else {
+                    Vec::new()
+                };
+
+                if let Some(delay) = args.page_delay_ms.map(Duration::from_millis)
+                    && let Some(last) = last_dispatch
+                {
+                    tokio::time::sleep_until((last + delay).into()).await;
+                }
+                last_dispatch = Some(Instant::now());
+
+                status!("Sending request to Ollama {:?}", ollama_url);
+                let outputs = outputs
+                    .into_iter()
+                    .map(|(path, conversations)| {
+                        // kept to ask again, more insistently, if the model refuses
+                        let retry_conversations = match args.retry_on_refusal {
+                            Some(_) => conversations
+                                .iter()
+                                .cloned()
+                                .map(|mut messages| {
+                                    if let Some(message) = messages.first_mut() {
+                                        message.content =
+                                            format!("{}{}", args.refusal_prompt, message.content);
+                                    }
+                                    messages
+                                })
+                                .collect(),
+                            None => Vec::new(),
+                        };
+                        (path, conversations, retry_conversations)
+                    })
+                    .collect::<Vec<_>>();
+                // written with the reason when the page fails
+                let error_path = outputs
+                    .first()
+                    .map(|(path, _, _)| path.with_extension("err"));
+
+                let slow_page_threshold = args.slow_page_threshold;
+                let max_output_bytes = args.max_output_bytes;
+                let output_formats = args.output_format.clone();
+                let min_free_disk = args.min_free_disk;
+                let abort_on_low_disk = args.abort_on_low_disk;
+                let cleanup_prompt = cleanup_prompt.clone();
+                let save_raw = args.save_raw;
+                let boilerplate = boilerplate.clone();
+                let page_label = args
+                    .strip_page_numbers
+                    .then(|| page.label().map(str::to_string))
+                    .flatten();
+                let strip_page_numbers = args.strip_page_numbers && !args.figures_only;
+                let translate = args.translate.clone().map(|lang| {
+                    let prompt = args.translate_prompt.replace("{lang}", &lang);
+                    (lang, prompt)
+                });
+                if args.html_review.is_some() {
+                    results.images.insert(page_no, page_images.clone());
+                }
+                // the review page links to the images, keep them
+                let keep_images = args.keep || args.prerender || args.html_review.is_some();
+                let page_images = PageImages(if keep_images { Vec::new() } else { page_images });
+                // the text layer the transcription should agree with
+                let reference_text = (args.verify_against_text && !args.figures_only)
+                    .then(|| lang::page_text(&page))
+                    .filter(|text| !text.trim().is_empty());
+                let verify_threshold = args.verify_threshold;
+                let keep_text = args.json_out.is_some()
+                    || args.html_review.is_some()
+                    || args.ground_truth.is_some()
+                    || args.merge.is_some()
+                    || args.combine
+                    || reference_text.is_some();
+                let save_raw_response = args.save_raw_response;
+                let page_schema = format_schema.clone();
+                let token_timing = token_timing.clone();
+                let timing_file = format!("\"{}\"", input_file.replace('"', "\"\""));
+                let refusal_retries = args.retry_on_refusal.unwrap_or(0);
+                let max_num_ctx = args.auto_grow_ctx.then_some(args.max_num_ctx);
+                let refusal_patterns = refusal_patterns.clone();
+                let page_metrics = metrics.clone();
+                let request_slots = request_slots.clone();
+                metrics.pages_total.fetch_add(1, Ordering::Relaxed);
+                let task = handles.spawn(with_error_file(error_path, async move {
+                    // held until the task ends, the streams only connect when polled;
+                    // one slot per request the page sends at once, taken together
+                    // so pages waiting for more never hold some
+                    let slots = (1 + ensemble.len()).min(concurrency);
+                    let _slots = request_slots.acquire_many_owned(slots as u32).await?;
+                    let page_slots = Arc::new(tokio::sync::Semaphore::new(slots));
+                    let mut ensemble_answers = JoinSet::new();
+                    for (prompt_no, conversations) in ensemble.into_iter().enumerate() {
+                        let (ollama, options) = (ollama.clone(), options.clone());
+                        let page_slots = page_slots.clone();
+                        ensemble_answers.spawn(async move {
+                            let _slot = page_slots.acquire_owned().await?;
+                            let (mut answer, mut tokens) = (String::new(), 0);
+                            for messages in &conversations {
+                                if !answer.is_empty() {
+                                    answer += "\n\n";
+                                }
+                                let (text, text_tokens) =
+                                    ollama.generate_counted(messages, &options).await?;
+                                answer += &text;
+                                tokens += text_tokens;
+                            }
+                            anyhow::Ok((prompt_no, answer, tokens))
+                        });
+                    }
+                    let _page_images = page_images;
+                    let _in_flight = page_metrics.page_started(&ollama_url);
+                    let mut request_start = Instant::now();
+                    let mut token_count = 0;
+                    let mut start = None;
+                    let mut total_duration = None;
+                    let mut truncated = None;
+                    let mut texts = Vec::new();
+                    let mut refused = false;
+                    let mut grown_ctx = None;
+                    let mut empty = true;
+                    let mut options = options;
+                    let mut timings = String::new();
+                    for (output_no, (content_path, conversations, retry_conversations)) in
+                        outputs.into_iter().enumerate()
+                    {
+                        let mut output_tokens = 0;
+                        let mut accumulated_response = String::new();
+                        let mut raw_responses = Vec::new();
+                        let mut chunk_no = 0;
+                        let stream_slot = page_slots.clone().acquire_owned().await?;
+                        'streams: for (stream_no, messages) in conversations.iter().enumerate() {
+                            if !accumulated_response.is_empty() {
+                                accumulated_response += "\n\n";
+                            }
+                            raw_responses.push(Vec::new());
+                            // to start over from if the answer breaks off
+                            let (stream_start, stream_tokens) =
+                                (accumulated_response.len(), output_tokens);
+                            let (timings_start, stream_chunk_no, stream_first_chunk) =
+                                (timings.len(), chunk_no, start);
+                            let mut stream = ollama.generate_stream(messages, &options);
+                            let mut stream_count = TokenCount::default();
+                            let mut attempt = 0;
+                            // set to send this answer's request again from scratch
+                            let mut restart = false;
+                            loop {
+                                if restart {
+                                    restart = false;
+                                    accumulated_response.truncate(stream_start);
+                                    output_tokens = stream_tokens;
+                                    stream_count = TokenCount::default();
+                                    timings.truncate(timings_start);
+                                    chunk_no = stream_chunk_no;
+                                    start = stream_first_chunk;
+                                    if start.is_none() {
+                                        // nothing came before this answer, time the page
+                                        // from the retry
+                                        request_start = Instant::now();
+                                    }
+                                    if let Some(chunks) = raw_responses.last_mut() {
+                                        chunks.clear();
+                                    }
+                                    stream = ollama.generate_stream(messages, &options);
+                                }
+                                let response = match stream.try_next().await {
+                                    Ok(Some(response)) => response,
+                                    Ok(None) => {
+                                        debug!(
+                                            "Page {}: {} tokens, {}",
+                                            page_no,
+                                            stream_count.tokens(),
+                                            if stream_count.is_exact() {
+                                                "from eval_count"
+                                            } else {
+                                                "counted from the streamed chunks"
+                                            }
+                                        );
+                                        break;
+                                    }
+                                    // a dropped connection, not an elapsed --timeout-secs
+                                    // (OllamaError::Timeout), which would elapse again;
+                                    // other servers also take the ones their client
+                                    // did not retry
+                                    Err(err)
+                                        if (matches!(err, OllamaError::Interrupted(_))
+                                            || (!nodes.is_empty() && err.is_transient()))
+                                            && attempt < retry.max_retries =>
+                                    {
+                                        attempt += 1;
+                                        let delay = retry.delay(attempt);
+                                        if let Some(failed) =
+                                            nodes.iter().position(|node| node.url() == ollama.url())
+                                        {
+                                            ollama = nodes[(failed + 1) % nodes.len()].clone();
+                                            ollama_url = ollama.url().to_string();
+                                        }
+                                        warn!(
+                                            "Page {}: {}, retrying on {} in {:?} ({}/{})",
+                                            page_no,
+                                            err,
+                                            ollama_url,
+                                            delay,
+                                            attempt,
+                                            retry.max_retries
+                                        );
+                                        tokio::time::sleep(delay).await;
+                                        restart = true;
+                                        continue;
+                                    }
+                                    Err(err) => return Err(err.into()),
+                                };
+                                if save_raw_response && let Some(chunks) = raw_responses.last_mut()
+                                {
+                                    chunks.push(response.clone());
+                                }
+                                if start.is_none() {
+                                    start = Some(Instant::now());
+                                }
+                                if token_timing.is_some() {
+                                    chunk_no += 1;
+                                    timings += &format!(
+                                        "{},{},{},{},{},{:.3},{}\n",
+                                        timing_file,
+                                        page_no,
+                                        ollama_url,
+                                        output_no + 1,
+                                        chunk_no,
+                                        request_start.elapsed().as_secs_f64() * 1000.0,
+                                        response.message.content.len()
+                                    );
+                                }
+                                if let Some(duration) = response.total_duration {
+                                    *total_duration.get_or_insert(0) += duration;
+                                }
+                                trace!("Response: {:?}", response);
+                                debug!(
+                                    "Processing response: done={}, text={}",
+                                    response.done, response.message.content
+                                );
+                                accumulated_response += &response.message.content;
+                                stream_count.add(&response);
+                                output_tokens = stream_tokens + stream_count.tokens();
+                                // the context window filled up before the answer ended
+                                if response.done_reason.as_deref() == Some("length")
+                                    && let Some(max_num_ctx) = max_num_ctx
+                                    && let Some(num_ctx) = options.num_ctx
+                                {
+                                    let grown = num_ctx.saturating_mul(2).min(max_num_ctx);
+                                    if grown_ctx.is_none() && grown > num_ctx {
+                                        warn!(
+                                            "Page {}: the answer filled --num-ctx {}, transcribing \
+                                            again with {}",
+                                            page_no, num_ctx, grown
+                                        );
+                                        options.num_ctx = Some(grown);
+                                        grown_ctx = Some(grown);
+                                        restart = true;
+                                        continue;
+                                    }
+                                    warn!(
+                                        "Page {}: the answer filled the {} token context window, \
+                                        truncated",
+                                        page_no, num_ctx
+                                    );
+                                    truncated = Some("--num-ctx");
+                                }
+                                // each column gets its own allowance, the next ones still run
+                                if stream_count.tokens() > args.max_tokens {
+                                    warn!(
+                                        "Page {}: answer {} of {} reached --max-tokens \
+                                        {}, truncating",
+                                        page_no,
+                                        stream_no + 1,
+                                        conversations.len(),
+                                        args.max_tokens
+                                    );
+                                    truncated = Some("--max-tokens");
+                                    break;
+                                }
+                                if let Some(cap) = max_output_bytes
+                                    && accumulated_response.len() > cap
+                                {
+                                    let mut end = cap;
+                                    while !accumulated_response.is_char_boundary(end) {
+                                        end -= 1;
+                                    }
+                                    accumulated_response.truncate(end);
+                                    warn!(
+                                        "Page {}: output exceeded {} bytes, truncating",
+                                        page_no, cap
+                                    );
+                                    if stream_no + 1 < conversations.len() {
+                                        warn!(
+                                            "Page {}: columns {}-{} dropped",
+                                            page_no,
+                                            stream_no + 2,
+                                            conversations.len()
+                                        );
+                                    }
+                                    truncated = Some("--max-output-bytes");
+                                    break 'streams;
+                                }
+                            }
+                        }
+                        token_count += output_tokens;
+                        drop(stream_slot);
+
+                        if save_raw_response {
+                            let responses = raw_responses
+                                .into_iter()
+                                .filter_map(OllamaResponse::merge)
+                                .collect::<Vec<_>>();
+                            std::fs::write(
+                                content_path.with_extension("response.json"),
+                                serde_json::to_string_pretty(&responses)?,
+                            )?;
+                        }
+
+                        if !ensemble_answers.is_empty() {
+                            let mut answers = Vec::new();
+                            while let Some(answer) = ensemble_answers.join_next().await {
+                                answers.push(answer??);
+                            }
+                            answers.sort_unstable_by_key(|(prompt_no, _, _)| *prompt_no);
+                            let mut candidates = vec![accumulated_response];
+                            for (_, answer, tokens) in answers {
+                                candidates.push(answer);
+                                token_count += tokens;
+                            }
+
+                            // the most complete answer wins
+                            let agreement = candidates
+                                .iter()
+                                .map(|candidate| postprocess::similarity(&candidates[0], candidate))
+                                .collect::<Vec<_>>();
+                            let best = (0..candidates.len())
+                                .max_by_key(|i| candidates[*i].len())
+                                .unwrap_or_default();
+                            status!(
+                                "- page {}: ensemble agreement with prompt 1 {:.2?}, \
+                                keeping prompt {}",
+                                page_no,
+                                agreement,
+                                best + 1
+                            );
+                            accumulated_response = candidates.swap_remove(best);
+                        }
+
+                        let mut retries = 0;
+                        while !retry_conversations.is_empty()
+                            && postprocess::is_refusal(&accumulated_response, &refusal_patterns)
+                        {
+                            if retries == refusal_retries {
+                                warn!(
+                                    "Page {}: model still refuses after {} retries",
+                                    page_no, retries
+                                );
+                                refused = true;
+                                break;
+                            }
+                            retries += 1;
+                            info!(
+                                "Page {}: model refused, retrying ({}/{})",
+                                page_no, retries, refusal_retries
+                            );
+                            let mut answer = String::new();
+                            for messages in &retry_conversations {
+                                if !answer.is_empty() {
+                                    answer += "\n\n";
+                                }
+                                let (text, tokens) =
+                                    ollama.generate_counted(messages, &options).await?;
+                                answer += &text;
+                                token_count += tokens;
+                            }
+                            accumulated_response = answer;
+                        }
+
+                        if let Some(cleanup_prompt) = &cleanup_prompt {
+                            if save_raw {
+                                output::write(
+                                    &output_formats,
+                                    &content_path.with_extension("raw.md"),
+                                    &accumulated_response,
+                                    page_no,
+                                    false,
+                                )?;
+                            }
+                            // second pass, text only: the model fixes its own OCR errors
+                            let messages = vec![ChatMessage {
+                                role: Role::User,
+                                content: format!("{}\n\n{}", cleanup_prompt, accumulated_response),
+                                thinking: None,
+                                images: None,
+                            }];
+                            let (text, tokens) =
+                                ollama.generate_counted(&messages, &options).await?;
+                            accumulated_response = text;
+                            token_count += tokens;
+                        }
+
+                        if let Some(phrases) = &boilerplate {
+                            accumulated_response =
+                                postprocess::strip_boilerplate(&accumulated_response, phrases);
+                        }
+
+                        if strip_page_numbers {
+                            accumulated_response = postprocess::strip_page_numbers(
+                                &accumulated_response,
+                                page_no,
+                                page_label.as_deref(),
+                            );
+                        }
+
+                        if let Some(schema) = &page_schema {
+                            let answer =
+                                serde_json::from_str(&accumulated_response).map_err(|err| {
+                                    anyhow::anyhow!(
+                                        "Page {}: the answer is not valid JSON despite \
+                                    --format-schema: {}",
+                                        page_no,
+                                        err
+                                    )
+                                })?;
+                            for violation in schema::violations(schema, &answer) {
+                                warn!(
+                                    "Page {}: answer does not match --format-schema, {}",
+                                    page_no, violation
+                                );
+                            }
+                        }
+
+                        if let Some(min_free) = min_free_disk {
+                            disk::ensure_free(
+                                content_path.parent().unwrap_or(Path::new(".")),
+                                min_free,
+                                abort_on_low_disk,
+                            )
+                            .await?;
+                        }
+                        output::write(
+                            &output_formats,
+                            &content_path,
+                            &accumulated_response,
+                            page_no,
+                            page_schema.is_some(),
+                        )?;
+
+                        if let Some((lang, translate_prompt)) = &translate {
+                            let messages = vec![ChatMessage {
+                                role: Role::User,
+                                content: format!(
+                                    "{}\n\n{}",
+                                    translate_prompt, accumulated_response
+                                ),
+                                thinking: None,
+                                images: None,
+                            }];
+                            let (mut translation, tokens) =
+                                ollama.generate_counted(&messages, &options).await?;
+                            token_count += tokens;
+                            if let Some(phrases) = &boilerplate {
+                                translation = postprocess::strip_boilerplate(&translation, phrases);
+                            }
+                            output::write(
+                                &output_formats,
+                                &content_path.with_extension(format!("{}.md", lang)),
+                                &translation,
+                                page_no,
+                                false,
+                            )?;
+                        }
+                        empty &= accumulated_response.trim().is_empty();
+                        if keep_text {
+                            texts.push(accumulated_response);
+                        }
+                    }
+                    let similarity = reference_text.map(|reference| {
+                        let similarity = postprocess::similarity(&reference, &texts.join("\n\n"));
+                        if similarity < verify_threshold {
+                            warn!(
+                                "Page {}: transcription shares only {:.0}% of its words \
+                                with the text layer",
+                                page_no,
+                                similarity * 100.0
+                            );
+                        }
+                        similarity
+                    });
+                    if let Some(file) = &token_timing {
+                        // one write per page keeps its rows together
+                        let mut file = file.lock().unwrap_or_else(PoisonError::into_inner);
+                        file.write_all(timings.as_bytes())?;
+                    }
+                    status!(
+                        " - page {} {:?}, {} tokens in {:?}",
+                        page_no,
+                        ollama_url,
+                        token_count,
+                        start.unwrap_or(request_start).elapsed()
                     );
-                    accumulated_response += &response.message.content;
-                    token_count += response.message.content.len();
-                    if token_count > args.max_tokens {
-                        info!("Max tokens reached, stopping stream");
-                        break;
+
+                    page_metrics.page_finished(&ollama_url, token_count, request_start.elapsed());
+                    page_metrics.pages_done.fetch_add(1, Ordering::Relaxed);
+
+                    if let Some(threshold) = slow_page_threshold {
+                        // prefer the server's own measurement, it excludes queueing on our side
+                        let elapsed = total_duration
+                            .map(|ns| Duration::from_nanos(ns as u64))
+                            .unwrap_or_else(|| request_start.elapsed());
+                        if elapsed.as_secs_f64() > threshold {
+                            warn!(
+                                "Slow page {} on {:?}: {:?} (threshold {}s)",
+                                page_no, ollama_url, elapsed, threshold
+                            );
+                        }
                     }
+
+                    anyhow::Ok(PageOutcome {
+                        page_no,
+                        tokens: token_count,
+                        truncated,
+                        text: keep_text.then(|| texts.join("\n\n")),
+                        similarity,
+                        refused,
+                        model,
+                        grown_ctx,
+                        empty,
+                    })
+                }));
+
+                results.task_pages.insert(task.id(), page_no);
+
+                // account for the pages done so far, so finished tasks are freed early
+                while let Some(result) = handles.try_join_next_with_id() {
+                    record_page(
+                        result,
+                        source,
+                        &mut stats,
+                        &mut checkpoint,
+                        &mut results,
+                        &metrics,
+                    )?;
                 }
-                println!(
-                    " - page {} {:?}, {} tokens in {:?}",
+                // --flush-interval-secs, so a crash loses at most that much
+                if flush_interval.is_some_and(|interval| last_flush.elapsed() >= interval) {
+                    flush_reports(&args, source, input_file, &results, &stats, &accuracy)?;
+                    failures_flushed |= !stats.failures.is_empty();
+                    if let Some(checkpoint) = &mut checkpoint {
+                        checkpoint.flush()?;
+                    }
+                    last_flush = Instant::now();
+                }
+                if too_many_failures(&stats) {
+                    break;
+                }
+            }
+
+            while !too_many_failures(&stats)
+                && let Some(result) = handles.join_next_with_id().await
+            {
+                record_page(
+                    result,
+                    source,
+                    &mut stats,
+                    &mut checkpoint,
+                    &mut results,
+                    &metrics,
+                )?;
+                // --flush-interval-secs, so a crash loses at most that much
+                if flush_interval.is_some_and(|interval| last_flush.elapsed() >= interval) {
+                    flush_reports(&args, source, input_file, &results, &stats, &accuracy)?;
+                    failures_flushed |= !stats.failures.is_empty();
+                    if let Some(checkpoint) = &mut checkpoint {
+                        checkpoint.flush()?;
+                    }
+                    last_flush = Instant::now();
+                }
+            }
+            let retry_pages = std::mem::take(&mut results.retry_pages);
+            if too_many_failures(&stats)
+                || retry_pages.is_empty()
+                || escalation >= args.retry_escalate_width.unwrap_or(0) as i32
+            {
+                break;
+            }
+            escalation += 1;
+            // the retried pages are accounted for again when their new tasks end
+            stats.pages -= retry_pages.len();
+            let failures = stats.failures.len();
+            stats
+                .failures
+                .retain(|(file, page_no, _)| file != source || !retry_pages.contains(page_no));
+            let retried_failures = failures - stats.failures.len();
+            stats.failed -= retried_failures;
+            metrics
+                .pages_failed
+                .fetch_sub(retried_failures, Ordering::Relaxed);
+            let truncated = results.truncated_pages.len();
+            results
+                .truncated_pages
+                .retain(|page_no, _| !retry_pages.contains(page_no));
+            stats.truncated -= truncated - results.truncated_pages.len();
+            metrics
+                .pages_planned
+                .fetch_add(retry_pages.len(), Ordering::Relaxed);
+            status!(
+                "{}: retrying pages {:?} at {:.0}% of the width (attempt {})",
+                input_file,
+                retry_pages,
+                ESCALATION_STEP.powi(escalation) * 100.0,
+                escalation
+            );
+            if !args.summary_only {
+                finalize_progress_bar();
+                init_progress_bar(retry_pages.len());
+            }
+            pass_pages = retry_pages.into_iter().collect();
+        }
+        if too_many_failures(&stats) {
+            handles.abort_all();
+            stats.aborted = Some(format!(
+                "{} pages failed, reaching --max-failures",
+                stats.failed
+            ));
+        }
+        if !args.summary_only {
+            finalize_progress_bar();
+        }
+        if let Some(checkpoint) = &mut checkpoint {
+            checkpoint.flush()?;
+        }
+
+        status!("{} processed in {:?}", input_file, start.elapsed());
+        if args.prerender {
+            status!(
+                "{} transcribed in {:?}",
+                input_file,
+                inference_start.elapsed()
+            );
+        }
+        if !results.truncated_pages.is_empty() {
+            let pages = results
+                .truncated_pages
+                .iter()
+                .map(|(page_no, limit)| format!("{} ({})", page_no, limit))
+                .collect::<Vec<_>>();
+            status!("Truncated pages: {}", pages.join(", "));
+        }
+
+        let disagreeing = results
+            .similarities
+            .iter()
+            .filter(|(_, similarity)| **similarity < args.verify_threshold)
+            .map(|(page_no, _)| *page_no)
+            .collect::<Vec<_>>();
+        if !disagreeing.is_empty() {
+            status!(
+                "Pages disagreeing with the text layer (--verify-against-text): {:?}",
+                disagreeing
+            );
+        }
+
+        if let Some(dir) = &args.ground_truth {
+            for (page_no, (text, _)) in &results.texts {
+                let name = naming::render_template(
+                    &args.name_template,
+                    &naming::Context {
+                        stem: naming::stem(input_file),
+                        page: *page_no,
+                        total: page_count as usize,
+                        ext: "md",
+                    },
+                )?;
+                let Some(reference) = eval::reference(Path::new(dir), &name) else {
+                    debug!("No reference transcription for {}", name);
+                    continue;
+                };
+                let scores = eval::score(&reference, text);
+                status!(
+                    " - page {}: CER {:.3}, WER {:.3}",
                     page_no,
-                    ollama_url,
-                    token_count,
-                    start.unwrap().elapsed()
+                    scores.cer,
+                    scores.wer
                 );
+                accuracy.push((source.to_string(), *page_no, scores));
+            }
+        }
 
-                std::fs::write(&content_path, accumulated_response).unwrap();
-            });
-            handles.push(handle);
-
-            pages_to_remove.push(image_path);
+        if let Some(path) = &args.json_out {
+            write_json_out(&args, path, source, input_file, &results)?;
         }
 
-        for handle in handles {
-            inc_progress_bar();
-            handle.await.unwrap();
+        if let Some(path) = &args.html_review {
+            let path = per_document_path(&args, path, input_file);
+            let pages = results
+                .texts
+                .iter()
+                .map(|(page_no, (text, _))| review::ReviewPage {
+                    page_no: *page_no,
+                    images: results.images.remove(page_no).unwrap_or_default(),
+                    markdown: text.clone(),
+                })
+                .collect::<Vec<_>>();
+            std::fs::write(&path, review::render(input_file, &path, &pages))?;
         }
-        finalize_progress_bar();
 
-        println!("{} processed in {:?}", input_file, start.elapsed());
+        if let Some(path) = &args.merge {
+            let path = per_document_path(&args, path, input_file);
+            let content = results
+                .texts
+                .iter()
+                .map(|(page_no, (text, _))| {
+                    if args.merge_headings {
+                        format!("## Page {}\n\n{}", page_no, text)
+                    } else {
+                        text.clone()
+                    }
+                })
+                .collect::<Vec<_>>()
+                .join("\n\n---\n\n");
+            let front_matter = metadata::front_matter(&document_metadata);
+            std::fs::write(&path, front_matter + &content + "\n")?;
+            status!("Merged transcription written to {:?}", path);
+        }
 
-        if !args.keep {
-            for page in pages_to_remove {
-                std::fs::remove_file(page).unwrap();
+        if args.combine {
+            let path = dir_path.join(combined_name(&args.combine_name, source)?);
+            if let Some(parent) = path.parent() {
+                std::fs::create_dir_all(parent)?;
+            }
+            // keyed by page number, so in page order whatever order tasks ended in
+            let content = results
+                .texts
+                .iter()
+                .map(|(page_no, (text, _))| format!("<!-- page {} -->\n\n{}", page_no, text))
+                .collect::<Vec<_>>()
+                .join("\n\n");
+            let front_matter = metadata::front_matter(&document_metadata);
+            let mut combined = front_matter + &content + "\n";
+            if args.append_summary {
+                let rows = results
+                    .texts
+                    .iter()
+                    .map(|(page_no, (_, tokens))| output::SummaryRow {
+                        page_no: *page_no,
+                        model: results.models.get(page_no).map(String::as_str),
+                        tokens: *tokens,
+                    })
+                    .collect::<Vec<_>>();
+                let now = std::time::SystemTime::now()
+                    .duration_since(std::time::UNIX_EPOCH)
+                    .unwrap_or_default();
+                combined += &format!("\n{}", output::summary_footer(&rows, now.as_secs()));
             }
+            std::fs::write(&path, combined)?;
+            status!("Combined transcription written to {:?}", path);
+        }
+
+        if let Some(reason) = &stats.aborted {
+            // what failed and where, to diagnose before resuming
+            let failures_path = write_failures(&args, &stats)?;
+            eprintln!(
+                "Run aborted: {}, failures written to {:?}",
+                reason, failures_path
+            );
+            break;
         }
     }
 
-    Ok(())
+    // a flushed failures.json is brought up to date, the pages it listed may
+    // have succeeded since on --retry-escalate-width
+    if failures_flushed && stats.aborted.is_none() {
+        if stats.failures.is_empty() {
+            std::fs::remove_file(Path::new(&args.output_dir).join("failures.json"))?;
+        } else {
+            write_failures(&args, &stats)?;
+        }
+    }
+
+    if args.summary_only {
+        let elapsed = start.elapsed();
+        println!("Summary:");
+        println!("  files:    {}", stats.files);
+        println!(
+            "  pages:    {} ({} failed, {} truncated)",
+            stats.pages, stats.failed, stats.truncated
+        );
+        println!("  tokens:   {}", stats.tokens);
+        if let Some(reason) = &stats.aborted {
+            println!("  aborted:  {}", reason);
+        }
+        if !stats.refused.is_empty() {
+            println!("  refused:  {}", stats.refused.len());
+        }
+        if !stats.grown_ctx.is_empty() {
+            println!("  grown ctx: {}", stats.grown_ctx.len());
+        }
+        if !stats.blank.is_empty() {
+            println!("  blank:    {}", stats.blank.len());
+        }
+        if !stats.languages.is_empty() {
+            println!("  languages: {:?}", stats.languages);
+        }
+        println!("  elapsed:  {:?}", elapsed);
+        println!(
+            "  tokens/s: {:.1}",
+            stats.tokens as f64 / elapsed.as_secs_f64().max(f64::EPSILON)
+        );
+        if let Some(peak) = metrics::peak_memory() {
+            println!("  peak RSS: {:.1} MiB", peak as f64 / (1024.0 * 1024.0));
+        }
+    }
+
+    if let Some(dir) = &args.ground_truth
+        && !args.metadata_only
+    {
+        if accuracy.is_empty() {
+            warn!("No page had a reference transcription in {:?}", dir);
+        } else {
+            let (mean_cer, mean_wer) = write_accuracy(&args, &accuracy)?;
+            println!(
+                "Accuracy over {} pages: mean CER {:.3}, mean WER {:.3}",
+                accuracy.len(),
+                mean_cer,
+                mean_wer
+            );
+            let report_path = Path::new(&args.output_dir).join("accuracy.json");
+            status!("Accuracy report written to {:?}", report_path);
+        }
+    }
+
+    if let Some(path) = &args.dump_outline
+        && !args.metadata_only
+    {
+        // one section per document when there are several
+        let content = match outlines.as_slice() {
+            [(_, outline)] => outline.clone(),
+            outlines => outlines
+                .iter()
+                .map(|(file, outline)| format!("# {}\n\n{}", file, outline))
+                .collect::<Vec<_>>()
+                .join("\n"),
+        };
+        std::fs::write(path, content)?;
+    }
+
+    if let Some(reporter) = progress_reporter {
+        reporter.abort();
+        eprintln!(
+            "{}",
+            metrics.progress_json(start.elapsed(), Some(Duration::ZERO))
+        );
+    }
+
+    if !args.summary_only && !stats.languages.is_empty() {
+        println!("Languages (pages): {:?}", stats.languages);
+    }
+
+    Ok(stats)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn png_header(args: &[&str], pixels: &[u8]) -> (png::ColorType, png::BitDepth) {
+        let args = Args::try_parse_from([&["pdftopng"], args].concat()).unwrap();
+        let encoded = encode_png(&args, pixels, 2, 1, 1, None).unwrap();
+        let reader = png::Decoder::new(std::io::Cursor::new(encoded))
+            .read_info()
+            .unwrap();
+        (reader.info().color_type, reader.info().bit_depth)
+    }
+
+    #[test]
+    fn png_color_type_follows_grayscale() {
+        let rgba = [10, 20, 30, 255, 40, 50, 60, 255];
+        assert_eq!(
+            png_header(&[], &rgba),
+            (png::ColorType::Rgba, png::BitDepth::Eight)
+        );
+        let gray = render::grayscale(&rgba, BitDepth::Eight);
+        // one byte per pixel instead of four
+        assert_eq!(gray.len() * 4, rgba.len());
+        assert_eq!(
+            png_header(&["--grayscale"], &gray),
+            (png::ColorType::Grayscale, png::BitDepth::Eight)
+        );
+        let gray16 = render::grayscale(&[0; 16], BitDepth::Sixteen);
+        assert_eq!(
+            png_header(&["--grayscale", "--bit-depth", "16"], &gray16),
+            (png::ColorType::Grayscale, png::BitDepth::Sixteen)
+        );
+    }
+
+    #[tokio::test]
+    async fn a_failed_write_fails_only_its_page() {
+        SUMMARY_ONLY.store(true, Ordering::Relaxed);
+        let dir = std::env::temp_dir().join(format!("pdftopng-write-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let mut handles = JoinSet::new();
+        let mut results = DocumentResults::default();
+        for page_no in 1..=3 {
+            // page 2 goes to a directory that does not exist
+            let content_path = match page_no {
+                2 => dir.join("missing").join("page-2.md"),
+                _ => dir.join(format!("page-{}.md", page_no)),
+            };
+            let error_path = dir.join(format!("page-{}.err", page_no));
+            let task = handles.spawn(with_error_file(Some(error_path), async move {
+                output::write(
+                    &[output::OutputFormat::Md],
+                    &content_path,
+                    "text",
+                    page_no,
+                    false,
+                )?;
+                anyhow::Ok(PageOutcome {
+                    page_no,
+                    ..Default::default()
+                })
+            }));
+            results.task_pages.insert(task.id(), page_no);
+        }
+        let mut stats = RunStats::default();
+        let metrics = Metrics::default();
+        while let Some(result) = handles.join_next_with_id().await {
+            record_page(
+                result,
+                "doc.pdf",
+                &mut stats,
+                &mut None,
+                &mut results,
+                &metrics,
+            )
+            .unwrap();
+        }
+
+        assert_eq!((stats.pages, stats.failed), (3, 1));
+        assert_eq!(stats.failures.len(), 1);
+        assert_eq!(stats.failures[0].1, 2);
+        assert_eq!(metrics.pages_failed.load(Ordering::Relaxed), 1);
+        assert!(dir.join("page-1.md").exists() && dir.join("page-3.md").exists());
+        assert!(dir.join("page-2.err").exists());
+        assert!(!dir.join("page-1.err").exists());
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn combined_names_follow_the_input_name() {
+        let name = |input| combined_name(naming::DEFAULT_COMBINED_TEMPLATE, input).unwrap();
+        assert_eq!(name("books/Vol.1.PDF"), "Vol.1.md");
+        assert_eq!(
+            name("https://example.com/scans/report?download=1"),
+            "report.md"
+        );
+        assert_eq!(
+            combined_name("{stem}.combined.md", "a/book.pdf").unwrap(),
+            "book.combined.md"
+        );
+    }
+
+    #[test]
+    fn duplicate_combined_names_are_an_error() {
+        let template = naming::DEFAULT_COMBINED_TEMPLATE;
+        let inputs = ["a/book.pdf", "b/other.pdf"].map(String::from);
+        assert!(check_combined_names(template, &inputs).is_ok());
+        assert!(check_combined_names("all.md", &inputs).is_err());
+        let inputs = ["a/book.pdf", "b/book.pdf"].map(String::from);
+        let err = check_combined_names(template, &inputs)
+            .unwrap_err()
+            .to_string();
+        assert!(
+            err.contains("a/book.pdf") && err.contains("b/book.pdf"),
+            "{}",
+            err
+        );
+        let inputs = ["book.pdf", "https://example.com/book.pdf"].map(String::from);
+        assert!(check_combined_names(template, &inputs).is_err());
+        assert!(check_combined_names("{page}.md", &inputs[..1]).is_err());
+    }
 }
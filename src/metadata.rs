@@ -0,0 +1,138 @@
+use pdfium_render::prelude::*;
+
+/// The document information fields worth reporting, with the names they
+/// are written under.
+const FIELDS: &[(&str, PdfDocumentMetadataTagType)] = &[
+    ("title", PdfDocumentMetadataTagType::Title),
+    ("author", PdfDocumentMetadataTagType::Author),
+    ("subject", PdfDocumentMetadataTagType::Subject),
+    ("keywords", PdfDocumentMetadataTagType::Keywords),
+    ("creator", PdfDocumentMetadataTagType::Creator),
+    ("producer", PdfDocumentMetadataTagType::Producer),
+    ("created", PdfDocumentMetadataTagType::CreationDate),
+    ("modified", PdfDocumentMetadataTagType::ModificationDate),
+];
+
+/// The document's information fields that are set, in a fixed order.
+/// Missing and blank fields are left out, dates are made ISO 8601 when
+/// they follow the PDF date format.
+pub fn extract(document: &PdfDocument) -> Vec<(&'static str, String)> {
+    let metadata = document.metadata();
+    FIELDS
+        .iter()
+        .filter_map(|(name, tag)| {
+            let value = metadata.get(*tag)?.value().trim().to_string();
+            if value.is_empty() {
+                return None;
+            }
+            match tag {
+                PdfDocumentMetadataTagType::CreationDate
+                | PdfDocumentMetadataTagType::ModificationDate => {
+                    Some((*name, iso_date(&value).unwrap_or(value)))
+                }
+                _ => Some((*name, value)),
+            }
+        })
+        .collect()
+}
+
+/// A YAML front matter block for the start of a Markdown file, or nothing
+/// when no field is set. Values are quoted as JSON strings, valid YAML.
+pub fn front_matter(fields: &[(&str, String)]) -> String {
+    if fields.is_empty() {
+        return String::new();
+    }
+    let mut out = String::from("---\n");
+    for (name, value) in fields {
+        out += &format!("{}: {}\n", name, serde_json::Value::from(value.as_str()));
+    }
+    out + "---\n\n"
+}
+
+/// "D:20230102150405+01'00'" as "2023-01-02T15:04:05+01:00". Missing
+/// trailing parts default as the PDF specification says.
+fn iso_date(value: &str) -> Option<String> {
+    let value = value.strip_prefix("D:").unwrap_or(value);
+    let digits = value.chars().take_while(char::is_ascii_digit).count();
+    if digits < 4 || digits % 2 != 0 {
+        return None;
+    }
+    let part = |start: usize, default: &'static str| {
+        value
+            .get(start..start + 2)
+            .filter(|_| start + 2 <= digits)
+            .unwrap_or(default)
+    };
+    let date = format!(
+        "{}-{}-{}T{}:{}:{}",
+        &value[..4],
+        part(4, "01"),
+        part(6, "01"),
+        part(8, "00"),
+        part(10, "00"),
+        part(12, "00")
+    );
+    let zone = match &value[digits..] {
+        "" => "",
+        zone if zone.starts_with('Z') => "Z",
+        zone => {
+            let zone = zone.replace('\'', "");
+            let (sign, offset) = zone.split_at_checked(1)?;
+            if !matches!(sign, "+" | "-") || !offset.chars().all(|c| c.is_ascii_digit()) {
+                return None;
+            }
+            let minutes = offset.get(2..4).unwrap_or("00");
+            return Some(format!("{}{}{}:{}", date, sign, offset.get(..2)?, minutes));
+        }
+    };
+    Some(date + zone)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn converts_full_pdf_dates() {
+        assert_eq!(
+            iso_date("D:20230102150405+01'00'").as_deref(),
+            Some("2023-01-02T15:04:05+01:00")
+        );
+        assert_eq!(
+            iso_date("D:20230102150405-0530").as_deref(),
+            Some("2023-01-02T15:04:05-05:30")
+        );
+        assert_eq!(
+            iso_date("D:20230102150405Z00'00'").as_deref(),
+            Some("2023-01-02T15:04:05Z")
+        );
+    }
+
+    #[test]
+    fn defaults_missing_parts() {
+        assert_eq!(iso_date("D:2023").as_deref(), Some("2023-01-01T00:00:00"));
+        assert_eq!(iso_date("202305").as_deref(), Some("2023-05-01T00:00:00"));
+        assert_eq!(
+            iso_date("D:2023010215+02").as_deref(),
+            Some("2023-01-02T15:00:00+02:00")
+        );
+    }
+
+    #[test]
+    fn rejects_other_formats() {
+        assert_eq!(iso_date("January 2, 2023"), None);
+        assert_eq!(iso_date("D:202"), None);
+        assert_eq!(iso_date("D:20230"), None);
+        assert_eq!(iso_date("D:20230102 15:04"), None);
+    }
+
+    #[test]
+    fn front_matter_quotes_values() {
+        let fields = [("title", "A \"quoted\": title".to_string())];
+        assert_eq!(
+            front_matter(&fields),
+            "---\ntitle: \"A \\\"quoted\\\": title\"\n---\n\n"
+        );
+        assert_eq!(front_matter(&[]), "");
+    }
+}
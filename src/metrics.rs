@@ -0,0 +1,254 @@
+use anyhow::Result;
+use log::{debug, info};
+use std::{
+    collections::{BTreeMap, VecDeque},
+    fmt::Write,
+    sync::{
+        Arc, Mutex,
+        atomic::{AtomicUsize, Ordering},
+    },
+    time::{Duration, Instant},
+};
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::TcpListener,
+    task::JoinHandle,
+};
+
+/// Snapshots the --progress-json throughput is averaged over.
+const ETA_WINDOW: usize = 10;
+
+/// Peak resident memory of this process in bytes, from /proc (Linux
+/// only), pdfium's allocations included.
+pub fn peak_memory() -> Option<u64> {
+    let status = std::fs::read_to_string("/proc/self/status").ok()?;
+    peak_from_status(&status)
+}
+
+fn peak_from_status(status: &str) -> Option<u64> {
+    let line = status
+        .lines()
+        .find_map(|line| line.strip_prefix("VmHWM:"))?;
+    let kib = line.trim().strip_suffix("kB")?.trim().parse::<u64>().ok()?;
+    Some(kib * 1024)
+}
+
+#[derive(Debug, Default, Clone)]
+pub struct BackendMetrics {
+    pub in_flight: usize,
+    pub pages: usize,
+    pub tokens: usize,
+    pub busy: Duration,
+}
+
+impl BackendMetrics {
+    pub fn tokens_per_sec(&self) -> f64 {
+        if self.busy.is_zero() {
+            0.0
+        } else {
+            self.tokens as f64 / self.busy.as_secs_f64()
+        }
+    }
+}
+
+/// Run-wide counters, shared by the page tasks and the reporters.
+#[derive(Debug, Default)]
+pub struct Metrics {
+    /// pages selected in the documents loaded so far, less the skipped ones
+    pub pages_planned: AtomicUsize,
+    pub pages_total: AtomicUsize,
+    pub pages_done: AtomicUsize,
+    pub pages_failed: AtomicUsize,
+    pub tokens: AtomicUsize,
+    backends: Mutex<BTreeMap<String, BackendMetrics>>,
+}
+
+/// Marks a request in flight on a backend until dropped, so a page task
+/// that panics still releases its slot.
+pub struct InFlight {
+    metrics: Arc<Metrics>,
+    backend: String,
+}
+
+impl Drop for InFlight {
+    fn drop(&mut self) {
+        if let Some(backend) = self.metrics.backends.lock().unwrap().get_mut(&self.backend) {
+            backend.in_flight -= 1;
+        }
+    }
+}
+
+impl Metrics {
+    pub fn page_started(self: &Arc<Self>, backend: &str) -> InFlight {
+        self.backends
+            .lock()
+            .unwrap()
+            .entry(backend.to_string())
+            .or_default()
+            .in_flight += 1;
+        InFlight {
+            metrics: self.clone(),
+            backend: backend.to_string(),
+        }
+    }
+
+    pub fn page_finished(&self, backend: &str, tokens: usize, elapsed: Duration) {
+        self.tokens.fetch_add(tokens, Ordering::Relaxed);
+        let mut backends = self.backends.lock().unwrap();
+        let backend = backends.entry(backend.to_string()).or_default();
+        backend.pages += 1;
+        backend.tokens += tokens;
+        backend.busy += elapsed;
+    }
+
+    pub fn backends(&self) -> BTreeMap<String, BackendMetrics> {
+        self.backends.lock().unwrap().clone()
+    }
+
+    /// One-line JSON snapshot of the run, for --progress-json.
+    pub fn progress_json(&self, elapsed: Duration, eta: Option<Duration>) -> String {
+        let in_flight = self
+            .backends()
+            .into_iter()
+            .map(|(url, backend)| (url, backend.in_flight))
+            .collect::<BTreeMap<_, _>>();
+        serde_json::json!({
+            "completed": self.pages_done.load(Ordering::Relaxed),
+            "failed": self.pages_failed.load(Ordering::Relaxed),
+            "total": self.pages_planned.load(Ordering::Relaxed),
+            "in_flight": in_flight,
+            "elapsed_secs": elapsed.as_secs_f64(),
+            "eta_secs": eta.map(|eta| eta.as_secs_f64()),
+        })
+        .to_string()
+    }
+
+    /// Prometheus text exposition format.
+    pub fn render_prometheus(&self) -> String {
+        let mut out = String::new();
+        let counters = [
+            (
+                "pdftopng_pages_total",
+                "Pages dispatched",
+                &self.pages_total,
+            ),
+            (
+                "pdftopng_pages_done_total",
+                "Pages completed",
+                &self.pages_done,
+            ),
+            (
+                "pdftopng_pages_failed_total",
+                "Pages failed",
+                &self.pages_failed,
+            ),
+            ("pdftopng_tokens_total", "Tokens generated", &self.tokens),
+        ];
+        for (name, help, value) in counters {
+            let _ = writeln!(out, "# HELP {} {}", name, help);
+            let _ = writeln!(out, "# TYPE {} counter", name);
+            let _ = writeln!(out, "{} {}", name, value.load(Ordering::Relaxed));
+        }
+
+        let backends = self.backends();
+        let _ = writeln!(
+            out,
+            "# HELP pdftopng_backend_in_flight Requests in flight per backend"
+        );
+        let _ = writeln!(out, "# TYPE pdftopng_backend_in_flight gauge");
+        for (url, backend) in &backends {
+            let _ = writeln!(
+                out,
+                "pdftopng_backend_in_flight{{backend={:?}}} {}",
+                url, backend.in_flight
+            );
+        }
+        let _ = writeln!(
+            out,
+            "# HELP pdftopng_backend_tokens_per_second Generation rate per backend"
+        );
+        let _ = writeln!(out, "# TYPE pdftopng_backend_tokens_per_second gauge");
+        for (url, backend) in &backends {
+            let _ = writeln!(
+                out,
+                "pdftopng_backend_tokens_per_second{{backend={:?}}} {:.3}",
+                url,
+                backend.tokens_per_sec()
+            );
+        }
+        out
+    }
+}
+
+/// Serve `metrics` on `addr` in the background. Every request gets the
+/// metrics page, whatever its path.
+pub async fn serve(addr: &str, metrics: Arc<Metrics>) -> Result<()> {
+    let listener = TcpListener::bind(addr).await?;
+    info!(
+        "Serving metrics on http://{}/metrics",
+        listener.local_addr()?
+    );
+
+    tokio::spawn(async move {
+        loop {
+            let Ok((mut socket, peer)) = listener.accept().await else {
+                continue;
+            };
+            let body = metrics.render_prometheus();
+            tokio::spawn(async move {
+                let mut request = [0u8; 1024];
+                let _ = socket.read(&mut request).await;
+                let response = format!(
+                    "HTTP/1.1 200 OK\r\nContent-Type: text/plain; version=0.0.4\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+                    body.len(),
+                    body
+                );
+                if let Err(err) = socket.write_all(response.as_bytes()).await {
+                    debug!("Metrics response to {} failed: {}", peer, err);
+                }
+            });
+        }
+    });
+    Ok(())
+}
+
+/// Print a progress snapshot to stderr every `interval` until the returned
+/// task is aborted. The ETA uses the throughput over the last few snapshots,
+/// so it follows the current pace rather than the run average.
+pub fn report_progress(metrics: Arc<Metrics>, interval: Duration) -> JoinHandle<()> {
+    let start = Instant::now();
+    tokio::spawn(async move {
+        let mut samples = VecDeque::with_capacity(ETA_WINDOW + 1);
+        let mut ticker = tokio::time::interval(interval);
+        loop {
+            ticker.tick().await;
+            let finished = metrics.pages_done.load(Ordering::Relaxed)
+                + metrics.pages_failed.load(Ordering::Relaxed);
+            samples.push_back((Instant::now(), finished));
+            if samples.len() > ETA_WINDOW {
+                samples.pop_front();
+            }
+
+            let (since, finished_then) = samples[0];
+            let rate = (finished - finished_then) as f64 / since.elapsed().as_secs_f64();
+            let remaining = metrics
+                .pages_planned
+                .load(Ordering::Relaxed)
+                .saturating_sub(finished);
+            let eta = (rate > 0.0).then(|| Duration::from_secs_f64(remaining as f64 / rate));
+            eprintln!("{}", metrics.progress_json(start.elapsed(), eta));
+        }
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn reads_peak_memory_from_proc_status() {
+        let status = "Name:\tpdftopng-rs\nVmPeak:\t  900000 kB\nVmHWM:\t   12345 kB\n";
+        assert_eq!(peak_from_status(status), Some(12345 * 1024));
+        assert_eq!(peak_from_status("Name:\tx\n"), None);
+    }
+}
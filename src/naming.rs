@@ -0,0 +1,197 @@
+use anyhow::Result;
+use std::path::{Path, PathBuf};
+
+/// File name pattern for page outputs, without a directory.
+pub const DEFAULT_TEMPLATE: &str = "{stem}-page-{page:06}.{ext}";
+
+/// File name pattern of the --combine output of each input.
+pub const DEFAULT_COMBINED_TEMPLATE: &str = "{stem}.md";
+
+/// Values substituted into a `--name-template`.
+pub struct Context<'a> {
+    /// input file name without its extension, whatever its case
+    pub stem: &'a str,
+    pub page: usize,
+    /// pages in the document
+    pub total: usize,
+    /// output file extension, without the leading dot
+    pub ext: &'a str,
+}
+
+/// Input file name without its extension: "Book.PDF" gives "Book".
+pub fn stem(input_file: &str) -> &str {
+    Path::new(input_file)
+        .file_stem()
+        .and_then(|stem| stem.to_str())
+        .unwrap_or(input_file)
+}
+
+/// Substitute `{stem}`, `{page}`, `{page:0N}` (zero-padded to N digits),
+/// `{total}` and `{ext}` in `template`. A template without `{ext}` gets
+/// ".ext" appended, so each kind of output keeps its own file.
+pub fn render_template(template: &str, ctx: &Context) -> Result<String> {
+    let mut out = String::new();
+    let mut has_ext = false;
+    let mut rest = template;
+    while let Some(open) = rest.find('{') {
+        out += &rest[..open];
+        let close = rest[open..]
+            .find('}')
+            .map(|close| open + close)
+            .ok_or_else(|| {
+                anyhow::anyhow!("Unclosed placeholder in --name-template {:?}", template)
+            })?;
+        let placeholder = &rest[open + 1..close];
+        match placeholder {
+            "stem" => out += ctx.stem,
+            "page" => out += &ctx.page.to_string(),
+            "total" => out += &ctx.total.to_string(),
+            "ext" => {
+                out += ctx.ext;
+                has_ext = true;
+            }
+            _ => match placeholder
+                .strip_prefix("page:0")
+                .and_then(|width| width.parse::<usize>().ok())
+            {
+                Some(width) => out += &format!("{:0width$}", ctx.page, width = width),
+                None => {
+                    return Err(anyhow::anyhow!(
+                        "Unknown placeholder {{{}}} in --name-template {:?}, expected {{stem}}, \
+                         {{page}}, {{page:0N}}, {{total}} or {{ext}}",
+                        placeholder,
+                        template
+                    ));
+                }
+            },
+        }
+        rest = &rest[close + 1..];
+    }
+    out += rest;
+    if !has_ext {
+        out = format!("{}.{}", out, ctx.ext);
+    }
+    Ok(out)
+}
+
+/// Substitute `{stem}` in a `--combine-name` template, the only placeholder
+/// that makes sense for a whole document.
+pub fn render_combined(template: &str, stem: &str) -> Result<String> {
+    if template.replace("{stem}", "").contains(['{', '}']) {
+        return Err(anyhow::anyhow!(
+            "Unknown placeholder in --combine-name {:?}, only {{stem}} is replaced",
+            template
+        ));
+    }
+    Ok(template.replace("{stem}", stem))
+}
+
+/// Check a template once, before any page: it must parse and name each page
+/// differently.
+pub fn validate(template: &str) -> Result<()> {
+    let name = |page| {
+        render_template(
+            template,
+            &Context {
+                stem: "document",
+                page,
+                total: 2,
+                ext: "png",
+            },
+        )
+    };
+    if name(1)? == name(2)? {
+        return Err(anyhow::anyhow!(
+            "--name-template {:?} gives every page the same name, add {{page}}",
+            template
+        ));
+    }
+    Ok(())
+}
+
+/// `path` with `suffix` inserted before its extension.
+pub fn with_suffix(path: &Path, suffix: &str) -> PathBuf {
+    let stem = path.file_stem().unwrap_or_default().to_string_lossy();
+    match path.extension() {
+        Some(ext) => path.with_file_name(format!("{}{}.{}", stem, suffix, ext.to_string_lossy())),
+        None => path.with_file_name(format!("{}{}", stem, suffix)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn render(template: &str) -> Result<String> {
+        render_template(
+            template,
+            &Context {
+                stem: "Book",
+                page: 7,
+                total: 120,
+                ext: "md",
+            },
+        )
+    }
+
+    #[test]
+    fn renders_the_default_template() {
+        assert_eq!(render(DEFAULT_TEMPLATE).unwrap(), "Book-page-000007.md");
+    }
+
+    #[test]
+    fn substitutes_every_placeholder() {
+        assert_eq!(
+            render("{stem}/p{page:03}-of-{total}.{ext}").unwrap(),
+            "Book/p007-of-120.md"
+        );
+        assert_eq!(render("{page}").unwrap(), "7.md");
+    }
+
+    #[test]
+    fn appends_the_extension_when_missing() {
+        assert_eq!(render("{stem}_{page:02}").unwrap(), "Book_07.md");
+    }
+
+    #[test]
+    fn rejects_bad_placeholders() {
+        assert!(render("{stem}-{pages}").is_err());
+        assert!(render("{page:3}").is_err());
+        assert!(render("{stem-{page}").is_err());
+        assert!(render("{page").is_err());
+    }
+
+    #[test]
+    fn validate_requires_distinct_names() {
+        assert!(validate(DEFAULT_TEMPLATE).is_ok());
+        assert!(validate("{stem}.{ext}").is_err());
+        assert!(validate("{stem}-{nope}").is_err());
+    }
+
+    #[test]
+    fn renders_combined_names() {
+        assert_eq!(
+            render_combined(DEFAULT_COMBINED_TEMPLATE, "Book").unwrap(),
+            "Book.md"
+        );
+        assert_eq!(
+            render_combined("{stem}/{stem}.combined.md", "Book").unwrap(),
+            "Book/Book.combined.md"
+        );
+        assert_eq!(render_combined("all.md", "Book").unwrap(), "all.md");
+        assert!(render_combined("{stem}-{page}.md", "Book").is_err());
+        assert!(render_combined("{stem.md", "Book").is_err());
+    }
+
+    #[test]
+    fn suffix_goes_before_the_extension() {
+        assert_eq!(
+            with_suffix(Path::new("out/a.page.md"), "-raw"),
+            Path::new("out/a.page-raw.md")
+        );
+        assert_eq!(
+            with_suffix(Path::new("out/a"), "-raw"),
+            Path::new("out/a-raw")
+        );
+    }
+}
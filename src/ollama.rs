@@ -1,10 +1,12 @@
-use anyhow::Result;
 use futures_util::{TryStreamExt, stream::Stream};
-use log::{error, debug, trace, info};
+use log::{error, debug, trace, warn};
 use reqwest::Client;
 use serde::{Deserialize, Serialize};
 use serde_json::Value;
-use std::pin::Pin;
+use std::{
+    pin::Pin,
+    time::{Duration, Instant},
+};
 use thiserror::Error;
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -48,6 +50,8 @@ pub struct GenerateOptions {
     pub top_p: Option<f32>,
     pub top_k: Option<i32>,
     pub num_predict: Option<i32>,
+    pub seed: Option<i64>,
+    pub num_ctx: Option<i32>,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -56,23 +60,9 @@ pub struct GenerateRequest {
     pub messages: Vec<ChatMessage>,
     pub options: GenerateOptions,
     pub stream: bool,
-    /*
-    "format": {
-        "type": "object",
-        "properties": {
-            "age": {
-                "type": "integer"
-            },
-            "available": {
-                "type": "boolean"
-            }
-        },
-        "required": [
-            "age",
-            "available"
-        ]
-    },
-    */
+    /// JSON schema the answer must follow
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub format: Option<Value>,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -101,6 +91,56 @@ pub struct OllamaResponse {
     pub metrics: Option<Value>,
 }
 
+impl OllamaResponse {
+    /// Fold the chunks of a streamed answer into one response: the last
+    /// chunk (done_reason, timings) carrying the whole content and thinking.
+    pub fn merge(chunks: Vec<OllamaResponse>) -> Option<OllamaResponse> {
+        let content = chunks
+            .iter()
+            .map(|chunk| chunk.message.content.as_str())
+            .collect::<String>();
+        let thinking = chunks
+            .iter()
+            .filter_map(|chunk| chunk.message.thinking.as_deref())
+            .collect::<String>();
+        let mut merged = chunks.into_iter().last()?;
+        merged.message.content = content;
+        merged.message.thinking = (!thinking.is_empty()).then_some(thinking);
+        Some(merged)
+    }
+}
+
+/// Tokens generated in one streamed answer: Ollama's `eval_count` from the
+/// final chunk once it arrives, until then the chunks carrying text, each
+/// holding one token.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct TokenCount {
+    chunks: usize,
+    eval_count: Option<usize>,
+}
+
+impl TokenCount {
+    pub fn add(&mut self, response: &OllamaResponse) {
+        if !response.message.content.is_empty()
+            || response.message.thinking.as_deref().is_some_and(|thinking| !thinking.is_empty())
+        {
+            self.chunks += 1;
+        }
+        if let Some(eval_count) = response.eval_count {
+            self.eval_count = Some(eval_count.max(0) as usize);
+        }
+    }
+
+    pub fn tokens(&self) -> usize {
+        self.eval_count.unwrap_or(self.chunks)
+    }
+
+    /// Whether the count comes from the server rather than from the chunks.
+    pub fn is_exact(&self) -> bool {
+        self.eval_count.is_some()
+    }
+}
+
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct ModelInfo {
     pub name: String,
@@ -116,8 +156,65 @@ pub enum OllamaError {
     ConnectionError(String),
     #[error("API error: {0}")]
     ApiError(String),
+    #[error("Server error: {0}")]
+    ServerError(String),
     #[error("Invalid response: {0}")]
     InvalidResponse(String),
+    #[error("Response interrupted: {0}")]
+    Interrupted(String),
+    #[error("Request timed out: {0}")]
+    Timeout(String),
+}
+
+/// Result of the Ollama client, failing with what went wrong.
+pub type Result<T, E = OllamaError> = std::result::Result<T, E>;
+
+impl OllamaError {
+    /// Whether the same request may succeed when sent again.
+    pub fn is_transient(&self) -> bool {
+        matches!(self, OllamaError::ConnectionError(_) | OllamaError::ServerError(_))
+    }
+}
+
+/// Longest wait before a retry, however many came before.
+const MAX_RETRY_DELAY: Duration = Duration::from_secs(30);
+
+/// Retries of a chat request that failed.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct RetryPolicy {
+    pub max_retries: usize,
+    /// delay before the first retry, doubled for each of the next ones
+    pub base_delay: Duration,
+}
+
+impl RetryPolicy {
+    /// Exponential delay before retry `attempt` (1-based), randomized by up
+    /// to 50% either way so clients that failed together do not retry together,
+    /// and capped at `MAX_RETRY_DELAY`.
+    pub fn delay(&self, attempt: usize) -> Duration {
+        let exponential = u32::try_from(attempt.saturating_sub(1))
+            .ok()
+            .and_then(|doublings| 2u32.checked_pow(doublings))
+            .and_then(|factor| self.base_delay.checked_mul(factor))
+            .map_or(MAX_RETRY_DELAY, |delay| delay.min(MAX_RETRY_DELAY));
+        let nanos = std::time::SystemTime::now()
+            .duration_since(std::time::UNIX_EPOCH)
+            .unwrap_or_default()
+            .subsec_nanos();
+        exponential
+            .mul_f64(0.5 + (nanos % 1000) as f64 / 1000.0)
+            .min(MAX_RETRY_DELAY)
+    }
+}
+
+/// Bearer token for an authenticating proxy, kept out of debug output.
+#[derive(Clone)]
+pub struct ApiKey(String);
+
+impl std::fmt::Debug for ApiKey {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str("ApiKey(<redacted>)")
+    }
 }
 
 #[derive(Debug, Clone)]
@@ -125,6 +222,19 @@ pub struct OllamaClient {
     base_url: String,
     model: String,
     count: usize,
+    /// connection pool, shared by the clones of this client so requests
+    /// reuse keep-alive connections
+    client: Client,
+    /// longest wait for the response to start, then between streamed chunks
+    request_timeout: Option<Duration>,
+    /// longest a whole request may take, streaming included
+    total_timeout: Option<Duration>,
+    retry: RetryPolicy,
+    /// sent as a leading system message with every chat request
+    system_prompt: Option<String>,
+    api_key: Option<ApiKey>,
+    /// JSON schema constraining chat answers
+    format: Option<Value>,
 }
 
 impl OllamaClient {
@@ -133,51 +243,127 @@ impl OllamaClient {
             base_url: base_url.to_string(),
             model: model.to_string(),
             count,
+            client: Client::new(),
+            request_timeout: None,
+            total_timeout: None,
+            retry: RetryPolicy::default(),
+            system_prompt: None,
+            api_key: None,
+            format: None,
+        }
+    }
+
+    /// Same server, giving up on connections, silent responses and whole
+    /// requests after these delays.
+    pub fn with_timeouts(
+        &self,
+        connect_timeout: Option<Duration>,
+        request_timeout: Option<Duration>,
+        total_timeout: Option<Duration>,
+    ) -> Result<Self> {
+        let mut builder = Client::builder();
+        if let Some(timeout) = connect_timeout {
+            builder = builder.connect_timeout(timeout);
+        }
+        // also bounds the requests not streamed, like the model list
+        if let Some(timeout) = request_timeout {
+            builder = builder.read_timeout(timeout);
+        }
+        if let Some(timeout) = total_timeout {
+            builder = builder.timeout(timeout);
+        }
+        let client = builder
+            .build()
+            .map_err(|err| OllamaError::ConnectionError(err.to_string()))?;
+        Ok(Self {
+            client,
+            request_timeout,
+            total_timeout,
+            ..self.clone()
+        })
+    }
+
+    /// Same server, retrying chat requests as `retry` says.
+    pub fn with_retries(&self, retry: RetryPolicy) -> Self {
+        Self {
+            retry,
+            ..self.clone()
+        }
+    }
+
+    /// Same server, starting every conversation with `system_prompt`. An
+    /// empty one sends no system message at all.
+    pub fn with_system_prompt(&self, system_prompt: Option<String>) -> Self {
+        Self {
+            system_prompt: system_prompt.filter(|prompt| !prompt.trim().is_empty()),
+            ..self.clone()
+        }
+    }
+
+    /// Same server, authenticating every request with `api_key` as a bearer token.
+    pub fn with_api_key(&self, api_key: Option<String>) -> Self {
+        Self {
+            api_key: api_key.map(ApiKey),
+            ..self.clone()
+        }
+    }
+
+    /// Same server, asking for answers following the JSON schema `format`.
+    pub fn with_format(&self, format: Option<Value>) -> Self {
+        Self {
+            format,
+            ..self.clone()
+        }
+    }
+
+    /// Same server, another model.
+    pub fn with_model(&self, model: &str) -> Self {
+        Self {
+            model: model.to_string(),
+            ..self.clone()
         }
     }
 
-    #[allow(unused)]
     pub fn url(&self) -> &str {
         &self.base_url
     }
 
-    #[allow(unused)]
     pub fn model(&self) -> &str {
         &self.model
     }
 
-    #[allow(unused)]
     pub fn count(&self) -> usize {
         self.count
     }
 
-    #[allow(unused)]
     pub async fn list_models(&self) -> Result<Vec<ModelInfo>> {
-        let client = Client::new();
+        let client = &self.client;
         let url = format!("{}/api/tags", self.base_url);
 
         debug!("Listing models from: {}", url);
 
-        let response = client.get(&url).send().await?;
+        let response = authorized(client.get(&url), self.api_key.as_ref())
+            .send()
+            .await
+            .map_err(|err| OllamaError::ConnectionError(format!("{}: {}", url, err)))?;
 
         debug!("Response status: {}", response.status());
 
-        if !response.status().is_success() {
-            let status = response.status();
-            let error_body = response.text().await?;
-            error!("Error response body: {}", error_body);
-            return Err(anyhow::anyhow!("Ollama API error: {}", status));
-        }
+        let response = check_status(&url, response).await?;
 
         #[derive(Deserialize)]
         struct ModelsResponse {
             models: Vec<ModelInfo>,
         }
 
-        let response_text = response.text().await?;
+        let response_text = response
+            .text()
+            .await
+            .map_err(|err| OllamaError::Interrupted(format!("{}: {}", url, err)))?;
         trace!("Response: {}", response_text);
 
-        let response: ModelsResponse = serde_json::from_str(&response_text)?;
+        let response: ModelsResponse = serde_json::from_str(&response_text)
+            .map_err(|err| OllamaError::InvalidResponse(format!("{}: {}", url, err)))?;
         debug!("Found {} models", response.models.len());
         for model in &response.models {
             debug!("- {} ({} bytes)", model.name, model.size);
@@ -191,11 +377,26 @@ impl OllamaClient {
         messages: &Vec<ChatMessage>,
         options: &GenerateOptions,
     ) -> Pin<Box<dyn Stream<Item = Result<OllamaResponse>> + Send>> {
-        let client = Client::new();
+        let client = self.client.clone();
         let url = format!("{}/api/chat", self.base_url.clone());
         let model = self.model.clone();
-        let messages = messages.clone();
+        let messages = self
+            .system_prompt
+            .iter()
+            .map(|system_prompt| ChatMessage {
+                role: Role::System,
+                content: system_prompt.clone(),
+                thinking: None,
+                images: None,
+            })
+            .chain(messages.iter().cloned())
+            .collect::<Vec<_>>();
         let options = options.clone();
+        let request_timeout = self.request_timeout;
+        let total_timeout = self.total_timeout;
+        let retry = self.retry;
+        let api_key = self.api_key.clone();
+        let format = self.format.clone();
     
         let fut = async_stream::try_stream! {
             let request = GenerateRequest {
@@ -203,15 +404,32 @@ impl OllamaClient {
                 messages,
                 options,
                 stream: true,
+                format,
             };
     
-            let resp = client
-                .post(&url)
-                .header("Accept", "application/x-ndjson") // pas obligatoire mais explicite
-                .json(&request)
-                .send()
-                .await?
-                .error_for_status()?;
+            // nothing has been streamed yet, so a retry cannot duplicate text
+            let mut attempt = 0;
+            let (resp, sent) = loop {
+                let sent = Instant::now();
+                let response = send_chat(
+                    &client,
+                    &url,
+                    &request,
+                    api_key.as_ref(),
+                    request_timeout,
+                    total_timeout,
+                );
+                match response.await {
+                    Ok(resp) => break (resp, sent),
+                    Err(err) if err.is_transient() && attempt < retry.max_retries => {
+                        attempt += 1;
+                        let delay = retry.delay(attempt);
+                        warn!("{}, retrying in {:?} ({}/{})", err, delay, attempt, retry.max_retries);
+                        tokio::time::sleep(delay).await;
+                    }
+                    Err(err) => Err(err)?,
+                }
+            };
     
             // Récupère un flux de chunks (Bytes)
             let mut stream = resp.bytes_stream();
@@ -219,7 +437,12 @@ impl OllamaClient {
             // Buffer pour gérer les JSON splités sur plusieurs chunks
             let mut buf = String::new();
     
-            while let Some(chunk) = stream.try_next().await? {
+            while let Some(chunk) = within(request_timeout, &url, stream.try_next())
+                .await?
+                .map_err(|err| {
+                    transport_error(&url, err, sent, total_timeout, OllamaError::Interrupted)
+                })?
+            {
                 // Append le chunk courant au buffer
                 let s = String::from_utf8_lossy(&chunk);
                 buf.push_str(&s);
@@ -230,17 +453,8 @@ impl OllamaClient {
                     let end = start + nl_pos;
                     let line = buf[start..end].trim();
                     if !line.is_empty() {
-                        match serde_json::from_str::<OllamaResponse>(line) {
-                            Ok(msg) => {
-                                // On émet l'élément streamé
-                                yield msg;
-                            }
-                            Err(e) => {
-                                // Si ça échoue ici, c'est probablement qu'on n'avait pas une ligne complète.
-                                // Mais comme on a trouvé un '\n', on log pour debug.
-                                debug!("JSON line parse error (will keep buffering): {e}; line=`{line}`");
-                            }
-                        }
+                        // On émet l'élément streamé
+                        yield parse_chunk(&url, line)?;
                     }
                     // on avance après ce '\n'
                     start = end + 1;
@@ -255,76 +469,434 @@ impl OllamaClient {
             // Fin du flux HTTP : s'il reste quelque chose dans le buffer sans '\n', tente un dernier parse
             let tail = buf.trim();
             if !tail.is_empty() {
-                if let Ok(msg) = serde_json::from_str::<OllamaResponse>(tail) {
-                    yield msg;
-                } else {
-                    debug!("Trailing partial JSON not parsed: `{tail}`");
-                }
+                yield parse_chunk(&url, tail)?;
             }
         };
     
         Box::pin(fut)
     }
 
-    #[allow(unused)]
-    pub fn generate_stream_old(&self, messages: &Vec<ChatMessage>, options: &GenerateOptions) -> Pin<Box<dyn Stream<Item = Result<OllamaResponse>> + Send>> {
-        let client = Client::new();
-        let url = format!("{}/api/chat", self.base_url.clone());
-        let model = self.model.clone();
-        let messages = messages.clone();
-        let options = options.clone();
+    /// Run a request to completion and return the whole answer and the
+    /// number of tokens generated.
+    pub async fn generate_counted(
+        &self,
+        messages: &Vec<ChatMessage>,
+        options: &GenerateOptions,
+    ) -> Result<(String, usize)> {
+        let mut stream = self.generate_stream(messages, options);
+        let mut content = String::new();
+        let mut count = TokenCount::default();
+        while let Some(response) = stream.try_next().await? {
+            count.add(&response);
+            content += &response.message.content;
+        }
+        Ok((content, count.tokens()))
+    }
+}
 
-        info!("Sending request to Ollama at: {}", url);
-        info!("Using model: {}", model);
-        info!("Options: {:?}", options);
-        debug!("Prompt: {:?}", messages);
+/// Await `future`, the next chunk of a streamed answer, failing as an
+/// interrupted response once `timeout` passes without it: the same error as
+/// reqwest's own read timeout, so the stall is retried whichever fires first.
+async fn within<T>(
+    timeout: Option<Duration>,
+    url: &str,
+    future: impl std::future::Future<Output = T>,
+) -> Result<T> {
+    match timeout {
+        Some(timeout) => tokio::time::timeout(timeout, future).await.map_err(|_| {
+            OllamaError::Interrupted(format!("{}: no chunk within {:?}", url, timeout))
+        }),
+        None => Ok(future.await),
+    }
+}
 
-        let fut = async_stream::try_stream! {
-            let request = GenerateRequest {
-                model,
-                messages,
-                options,
-                stream: true,
-            };
-            debug!("request: {:?}", request);
-            let response = client
-                .post(&url)
-                .json(&request)
-                .send()
-                .await?;
-            let response = response.error_for_status().map_err(|e| {
-                anyhow::anyhow!("Ollama API error: {}", e)
-            })?;
-            debug!("response: {:?}", response);
-            let bytes = response.bytes().await?;
-            let text = String::from_utf8_lossy(&bytes).into_owned();
-            let mut buffer = text;
-            let mut new_buffer = String::new();
-            for line in buffer.lines() {
-                let line = line.trim();
-                if line.is_empty() {
-                    continue;
-                }
-                debug!("line: {}", line);
-                match serde_json::from_str::<OllamaResponse>(line) {
-                    Ok(response) => {
-                        yield response;
-                    }
-                    Err(err) => {
-                        error!("Error parsing line: {}", err);
-                        new_buffer.push_str(line);
-                        new_buffer.push('\n');
-                    }
-                }
+/// One try at a chat request, up to the response headers.
+async fn send_chat(
+    client: &Client,
+    url: &str,
+    request: &GenerateRequest,
+    api_key: Option<&ApiKey>,
+    timeout: Option<Duration>,
+    total_timeout: Option<Duration>,
+) -> Result<reqwest::Response> {
+    let sent = Instant::now();
+    let send = authorized(client.post(url), api_key)
+        .header("Accept", "application/x-ndjson")
+        .json(request)
+        .send();
+    let resp = match timeout {
+        Some(timeout) => tokio::time::timeout(timeout, send).await.map_err(|_| {
+            OllamaError::ConnectionError(format!("{}: no answer within {:?}", url, timeout))
+        })?,
+        None => send.await,
+    }
+    .map_err(|err| transport_error(url, err, sent, total_timeout, OllamaError::ConnectionError))?;
+    check_status(url, resp).await
+}
+
+/// What a reqwest failure of a request sent at `sent` means: `Timeout` once
+/// the whole-request deadline has passed, which sending again cannot fix,
+/// otherwise the transport failure `dropped` (a lost connection, a silent
+/// server).
+fn transport_error(
+    url: &str,
+    err: reqwest::Error,
+    sent: Instant,
+    total_timeout: Option<Duration>,
+    dropped: fn(String) -> OllamaError,
+) -> OllamaError {
+    match total_timeout {
+        Some(total) if err.is_timeout() && sent.elapsed() >= total => {
+            OllamaError::Timeout(format!("{}: not finished within {:?}", url, total))
+        }
+        _ => dropped(format!("{}: {}", url, err)),
+    }
+}
+
+/// `request` with the bearer token, if any. reqwest marks the header
+/// sensitive, so it is redacted when the request is logged.
+fn authorized(
+    request: reqwest::RequestBuilder,
+    api_key: Option<&ApiKey>,
+) -> reqwest::RequestBuilder {
+    match api_key {
+        Some(ApiKey(token)) => request.bearer_auth(token),
+        None => request,
+    }
+}
+
+/// `resp` if it succeeded, else an error carrying the status and the
+/// server's explanation from the body.
+async fn check_status(url: &str, resp: reqwest::Response) -> Result<reqwest::Response> {
+    let status = resp.status();
+    if status.is_success() {
+        return Ok(resp);
+    }
+    let body = resp.text().await.unwrap_or_default();
+    error!("Error response body: {}", body);
+    // Ollama explains errors as {"error": "..."}
+    let message = serde_json::from_str::<Value>(&body)
+        .ok()
+        .and_then(|body| body.get("error")?.as_str().map(str::to_string))
+        .unwrap_or(body);
+    let message = format!("{}: {} {}", url, status, message.trim());
+    if status.is_server_error() {
+        Err(OllamaError::ServerError(message))
+    } else {
+        Err(OllamaError::ApiError(message))
+    }
+}
+
+/// One line of a streamed chat answer. Ollama reports failures after the
+/// response has started as an {"error": "..."} line.
+fn parse_chunk(url: &str, line: &str) -> Result<OllamaResponse> {
+    serde_json::from_str::<OllamaResponse>(line).map_err(|err| {
+        match serde_json::from_str::<Value>(line)
+            .ok()
+            .and_then(|line| line.get("error")?.as_str().map(str::to_string))
+        {
+            Some(message) => OllamaError::ApiError(format!("{}: {}", url, message)),
+            None => OllamaError::InvalidResponse(format!("{}: {}; line=`{}`", url, err, line)),
+        }
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+    use std::sync::{
+        Arc,
+        atomic::{AtomicUsize, Ordering},
+    };
+
+    fn options(seed: Option<i64>) -> GenerateOptions {
+        GenerateOptions {
+            temperature: None,
+            top_p: None,
+            top_k: None,
+            num_predict: None,
+            seed,
+            num_ctx: None,
+        }
+    }
+
+    fn request(format: Option<Value>) -> Value {
+        let request = GenerateRequest {
+            model: "llava".to_string(),
+            messages: vec![ChatMessage {
+                role: Role::User,
+                content: "Transcribe".to_string(),
+                thinking: None,
+                images: None,
+            }],
+            options: options(Some(7)),
+            stream: true,
+            format,
+        };
+        serde_json::to_value(&request).unwrap()
+    }
+
+    fn chunk(content: &str, eval_count: Option<i32>) -> OllamaResponse {
+        serde_json::from_value(json!({
+            "model": "llava",
+            "created_at": "2024-01-01T00:00:00Z",
+            "message": {"role": "assistant", "content": content},
+            "done": eval_count.is_some(),
+            "eval_count": eval_count,
+        }))
+        .unwrap()
+    }
+
+    #[test]
+    fn token_count_falls_back_to_chunks() {
+        let mut count = TokenCount::default();
+        for content in ["Hel", "lo", "", " world"] {
+            count.add(&chunk(content, None));
+        }
+        assert_eq!(count.tokens(), 3);
+        assert!(!count.is_exact());
+    }
+
+    #[test]
+    fn token_count_prefers_eval_count() {
+        let mut count = TokenCount::default();
+        count.add(&chunk("Hello", None));
+        count.add(&chunk(" world", None));
+        count.add(&chunk("", Some(12)));
+        assert_eq!(count.tokens(), 12);
+        assert!(count.is_exact());
+    }
+
+    #[test]
+    fn retry_delay_doubles_up_to_the_cap() {
+        let retry = RetryPolicy {
+            max_retries: 100,
+            base_delay: Duration::from_millis(100),
+        };
+        let first = retry.delay(1);
+        assert!(first >= Duration::from_millis(50) && first <= Duration::from_millis(150));
+        let third = retry.delay(3);
+        assert!(third >= Duration::from_millis(200) && third <= Duration::from_millis(600));
+        for attempt in [10, 33, 64, 1000, usize::MAX] {
+            assert!(retry.delay(attempt) <= MAX_RETRY_DELAY, "{}", attempt);
+            assert!(retry.delay(attempt) >= MAX_RETRY_DELAY / 2, "{}", attempt);
+        }
+        let huge = RetryPolicy {
+            max_retries: 1,
+            base_delay: Duration::MAX,
+        };
+        assert!(huge.delay(2) <= MAX_RETRY_DELAY);
+    }
+
+    /// Read one request off `socket`: headers, then as many bytes as they
+    /// announce. False once the client has closed the connection.
+    async fn read_request(socket: &mut tokio::net::TcpStream) -> bool {
+        use tokio::io::AsyncReadExt;
+        let mut request = Vec::new();
+        let mut buf = [0; 4096];
+        loop {
+            let read = socket.read(&mut buf).await.unwrap_or(0);
+            if read == 0 {
+                return false;
             }
-            buffer = new_buffer;
-            debug!("no more chunk on stream");
-            if !buffer.trim().is_empty() {
-                if let Ok(response) = serde_json::from_str::<OllamaResponse>(buffer.trim()) {
-                    yield response;
+            request.extend_from_slice(&buf[..read]);
+            let text = String::from_utf8_lossy(&request).to_lowercase();
+            if let Some(end) = text.find("\r\n\r\n") {
+                let length = text
+                    .lines()
+                    .find_map(|line| line.strip_prefix("content-length:"))
+                    .and_then(|length| length.trim().parse::<usize>().ok())
+                    .unwrap_or(0);
+                if request.len() >= end + 4 + length {
+                    return true;
                 }
             }
-        };
-        Box::pin(fut)
+        }
+    }
+
+    /// Answer each connection in turn with one of `responses`, after reading
+    /// the request.
+    async fn serve(responses: Vec<String>) -> String {
+        use tokio::io::AsyncWriteExt;
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap();
+        tokio::spawn(async move {
+            for response in responses {
+                let (mut socket, _) = listener.accept().await.unwrap();
+                read_request(&mut socket).await;
+                socket.write_all(response.as_bytes()).await.unwrap();
+                socket.shutdown().await.unwrap();
+            }
+        });
+        format!("http://{}", address)
+    }
+
+    /// Answer every request with `body`, keeping the connections open, and
+    /// count the connections accepted.
+    async fn serve_keep_alive(body: &str) -> (String, Arc<AtomicUsize>) {
+        use tokio::io::AsyncWriteExt;
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap();
+        let response = format!(
+            "HTTP/1.1 200 OK\r\ncontent-length: {}\r\n\r\n{}",
+            body.len(),
+            body
+        );
+        let connections = Arc::new(AtomicUsize::new(0));
+        let accepted = connections.clone();
+        tokio::spawn(async move {
+            loop {
+                let (mut socket, _) = listener.accept().await.unwrap();
+                accepted.fetch_add(1, Ordering::SeqCst);
+                let response = response.clone();
+                tokio::spawn(async move {
+                    while read_request(&mut socket).await {
+                        socket.write_all(response.as_bytes()).await.unwrap();
+                    }
+                });
+            }
+        });
+        (format!("http://{}", address), connections)
+    }
+
+    /// Answer one connection with `head`, then `tail` after `stall`.
+    async fn serve_stalled(head: String, stall: Duration, tail: String) -> String {
+        use tokio::io::AsyncWriteExt;
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap();
+        tokio::spawn(async move {
+            let (mut socket, _) = listener.accept().await.unwrap();
+            read_request(&mut socket).await;
+            // the client may have given up and closed the connection
+            let _ = socket.write_all(head.as_bytes()).await;
+            tokio::time::sleep(stall).await;
+            let _ = socket.write_all(tail.as_bytes()).await;
+        });
+        format!("http://{}", address)
+    }
+
+    fn http_response(status: &str, body: &str) -> String {
+        format!(
+            "HTTP/1.1 {}\r\ncontent-length: {}\r\nconnection: close\r\n\r\n{}",
+            status,
+            body.len(),
+            body
+        )
+    }
+
+    fn user_message() -> Vec<ChatMessage> {
+        vec![ChatMessage {
+            role: Role::User,
+            content: "Transcribe".to_string(),
+            thinking: None,
+            images: None,
+        }]
+    }
+
+    #[tokio::test]
+    async fn retries_server_errors_until_success() {
+        let overloaded = http_response("503 Service Unavailable", r#"{"error":"busy"}"#);
+        let answer = [chunk("Hel", None), chunk("lo", Some(2))]
+            .map(|chunk| serde_json::to_string(&chunk).unwrap())
+            .join("\n");
+        let base_url = serve(vec![
+            overloaded.clone(),
+            overloaded,
+            http_response("200 OK", &answer),
+        ])
+        .await;
+        let client = OllamaClient::new(&base_url, "llava", 1).with_retries(RetryPolicy {
+            max_retries: 2,
+            base_delay: Duration::from_millis(1),
+        });
+        let (content, tokens) = client
+            .generate_counted(&user_message(), &options(None))
+            .await
+            .unwrap();
+        assert_eq!((content.as_str(), tokens), ("Hello", 2));
+    }
+
+    #[tokio::test]
+    async fn gives_up_after_max_retries() {
+        let overloaded = http_response("503 Service Unavailable", r#"{"error":"busy"}"#);
+        let base_url = serve(vec![overloaded.clone(), overloaded]).await;
+        let client = OllamaClient::new(&base_url, "llava", 1).with_retries(RetryPolicy {
+            max_retries: 1,
+            base_delay: Duration::from_millis(1),
+        });
+        let err = client
+            .generate_counted(&user_message(), &options(None))
+            .await
+            .unwrap_err();
+        assert!(matches!(&err, OllamaError::ServerError(message) if message.contains("busy")));
+    }
+
+    #[tokio::test]
+    async fn gives_up_on_a_silent_server() {
+        let answer = serde_json::to_string(&chunk("Hello", Some(1))).unwrap();
+        let base_url = serve_stalled(
+            String::new(),
+            Duration::from_secs(5),
+            http_response("200 OK", &answer),
+        )
+        .await;
+        let client = OllamaClient::new(&base_url, "llava", 1)
+            .with_timeouts(None, Some(Duration::from_millis(100)), None)
+            .unwrap();
+        let started = Instant::now();
+        let err = client
+            .generate_counted(&user_message(), &options(None))
+            .await
+            .unwrap_err();
+        assert!(matches!(err, OllamaError::ConnectionError(_)), "{:?}", err);
+        assert!(started.elapsed() < Duration::from_secs(5));
+    }
+
+    #[tokio::test]
+    async fn a_stalled_stream_is_interrupted() {
+        let head = format!(
+            "HTTP/1.1 200 OK\r\nconnection: close\r\n\r\n{}\n",
+            serde_json::to_string(&chunk("Hel", None)).unwrap()
+        );
+        let tail = serde_json::to_string(&chunk("lo", Some(2))).unwrap();
+        let base_url = serve_stalled(head, Duration::from_secs(5), tail).await;
+        let client = OllamaClient::new(&base_url, "llava", 1)
+            .with_timeouts(None, Some(Duration::from_millis(100)), None)
+            .unwrap();
+        let mut stream = client.generate_stream(&user_message(), &options(None));
+        let first = stream.try_next().await.unwrap().unwrap();
+        assert_eq!(first.message.content, "Hel");
+        let err = stream.try_next().await.unwrap_err();
+        assert!(matches!(err, OllamaError::Interrupted(_)), "{:?}", err);
+    }
+
+    #[tokio::test]
+    async fn list_models_reuses_the_connection() {
+        let body = r#"{"models":[{"name":"llava","size":1,"digest":"abc","details":null}]}"#;
+        let (base_url, connections) = serve_keep_alive(body).await;
+        let client = OllamaClient::new(&base_url, "llava", 1);
+        // clones share the pool, as the per-node clients of a run do
+        let clone = client.with_model("qwen2.5vl");
+        for client in [&client, &clone, &client] {
+            let models = client.list_models().await.unwrap();
+            assert_eq!(models[0].name, "llava");
+        }
+        assert_eq!(connections.load(Ordering::SeqCst), 1);
+    }
+
+    #[test]
+    fn request_carries_the_format_schema() {
+        let schema = json!({"type": "object", "properties": {"text": {"type": "string"}}});
+        let sent = request(Some(schema.clone()));
+        assert_eq!(sent["format"], schema);
+        assert_eq!(sent["messages"][0]["role"], "user");
+        assert_eq!(sent["options"]["seed"], 7);
+    }
+
+    #[test]
+    fn request_leaves_out_a_missing_format() {
+        let sent = request(None);
+        assert!(sent.as_object().unwrap().get("format").is_none());
     }
 }
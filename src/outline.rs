@@ -0,0 +1,49 @@
+use log::warn;
+use pdfium_render::prelude::*;
+use std::fmt::Write;
+
+/// Deepest bookmark level followed, a guard against children pointing back
+/// at their ancestors in malformed outlines.
+const MAX_DEPTH: usize = 32;
+
+/// Most bookmarks listed, a guard against sibling chains that loop.
+const MAX_ENTRIES: usize = 10_000;
+
+/// The document's bookmark tree as a nested Markdown list, each entry with
+/// the page it points to. None when the document has no outline.
+pub fn outline_markdown(document: &PdfDocument) -> Option<String> {
+    let bookmarks = document.bookmarks();
+    let root = bookmarks.root()?;
+    let mut out = String::new();
+    let mut remaining = MAX_ENTRIES;
+    walk(Some(root), 0, &mut remaining, &mut out);
+    if remaining == 0 {
+        warn!("Outline cut at {} entries, it may loop", MAX_ENTRIES);
+    }
+    Some(out)
+}
+
+/// List `bookmark` and its next siblings at `depth`, each followed by its
+/// children, at most `remaining` entries in all.
+fn walk(mut bookmark: Option<PdfBookmark>, depth: usize, remaining: &mut usize, out: &mut String) {
+    if depth >= MAX_DEPTH {
+        return;
+    }
+    while let Some(current) = bookmark {
+        if *remaining == 0 {
+            return;
+        }
+        *remaining -= 1;
+        let title = current.title().unwrap_or_default();
+        let page = current
+            .destination()
+            .and_then(|destination| destination.page_index().ok());
+        let indent = "  ".repeat(depth);
+        let _ = match page {
+            Some(index) => writeln!(out, "{}- {} (p. {})", indent, title.trim(), index + 1),
+            None => writeln!(out, "{}- {}", indent, title.trim()),
+        };
+        walk(current.first_child(), depth + 1, remaining, out);
+        bookmark = current.next_sibling();
+    }
+}
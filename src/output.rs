@@ -0,0 +1,260 @@
+use clap::ValueEnum;
+use std::path::Path;
+
+/// Representations written for each model response, all from the same text.
+#[derive(ValueEnum, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum OutputFormat {
+    /// the response as is
+    Md,
+    /// {"page": N, "content": "..."}, or the answer itself as "content"
+    /// when it is JSON (--format-schema)
+    Json,
+    /// the response with Markdown markup removed
+    Txt,
+}
+
+impl OutputFormat {
+    pub fn extension(self) -> &'static str {
+        match self {
+            OutputFormat::Md => "md",
+            OutputFormat::Json => "json",
+            OutputFormat::Txt => "txt",
+        }
+    }
+
+    /// `content` as this format. With `json_answer`, the content is a JSON
+    /// document and goes into .json parsed rather than as a string.
+    pub fn render(self, content: &str, page_no: usize, json_answer: bool) -> String {
+        match self {
+            OutputFormat::Md => content.to_string(),
+            OutputFormat::Json => {
+                let content = json_answer
+                    .then(|| serde_json::from_str::<serde_json::Value>(content).ok())
+                    .flatten()
+                    .unwrap_or_else(|| content.into());
+                serde_json::to_string_pretty(&serde_json::json!({
+                    "page": page_no,
+                    "content": content,
+                }))
+                .unwrap_or_default()
+            }
+            OutputFormat::Txt => plain_text(content),
+        }
+    }
+}
+
+/// Write `content` once per format, next to `path` with the format's extension.
+pub fn write(
+    formats: &[OutputFormat],
+    path: &Path,
+    content: &str,
+    page_no: usize,
+    json_answer: bool,
+) -> std::io::Result<()> {
+    for format in formats {
+        std::fs::write(
+            path.with_extension(format.extension()),
+            format.render(content, page_no, json_answer),
+        )?;
+    }
+    Ok(())
+}
+
+/// Replace `path` with `content` through a temporary file next to it, so a
+/// crash mid-write leaves the previous version intact.
+pub fn write_atomic(path: &Path, content: impl AsRef<[u8]>) -> std::io::Result<()> {
+    let mut tmp = path.as_os_str().to_owned();
+    tmp.push(".tmp");
+    std::fs::write(&tmp, content)?;
+    std::fs::rename(&tmp, path)
+}
+
+/// One page in the --append-summary table: its number, the model that
+/// transcribed it (None when reused from an earlier run) and its tokens.
+pub struct SummaryRow<'a> {
+    pub page_no: usize,
+    pub model: Option<&'a str>,
+    pub tokens: usize,
+}
+
+/// Markdown section closing a combined document: page count, models, date
+/// (`unix_secs`, UTC) and a per-page token table.
+pub fn summary_footer(rows: &[SummaryRow], unix_secs: u64) -> String {
+    let mut models = rows.iter().filter_map(|row| row.model).collect::<Vec<_>>();
+    models.sort_unstable();
+    models.dedup();
+    let reused = rows.iter().filter(|row| row.model.is_none()).count();
+
+    let mut out = String::from("## Summary\n\n");
+    out += &format!("- Pages: {}", rows.len());
+    if reused > 0 {
+        out += &format!(" ({} from an earlier run)", reused);
+    }
+    out += &format!("\n- Model: {}\n", models.join(", "));
+    out += &format!("- Date: {}\n\n", utc_date(unix_secs));
+    out += "| Page | Model | Tokens |\n|---:|---|---:|\n";
+    for row in rows {
+        match row.model {
+            Some(model) => out += &format!("| {} | {} | {} |\n", row.page_no, model, row.tokens),
+            None => out += &format!("| {} | - | - |\n", row.page_no),
+        }
+    }
+    let total = rows.iter().map(|row| row.tokens).sum::<usize>();
+    out += &format!("| Total | | {} |\n", total);
+    out
+}
+
+/// "YYYY-MM-DD" of a Unix time, in UTC.
+fn utc_date(unix_secs: u64) -> String {
+    // days to civil date, from Howard Hinnant's date algorithms
+    let days = (unix_secs / 86400) as i64 + 719468;
+    let era = days.div_euclid(146097);
+    let day_of_era = days.rem_euclid(146097);
+    let year_of_era =
+        (day_of_era - day_of_era / 1460 + day_of_era / 36524 - day_of_era / 146096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let month_index = (5 * day_of_year + 2) / 153;
+    let day = day_of_year - (153 * month_index + 2) / 5 + 1;
+    let month = if month_index < 10 {
+        month_index + 3
+    } else {
+        month_index - 9
+    };
+    let year = year_of_era + era * 400 + i64::from(month <= 2);
+    format!("{:04}-{:02}-{:02}", year, month, day)
+}
+
+/// Why `formats` cannot be written together, or for answers following a
+/// --format-schema when `json_answer`.
+pub fn check(formats: &[OutputFormat], json_answer: bool) -> anyhow::Result<()> {
+    for (i, format) in formats.iter().enumerate() {
+        if formats[..i].contains(format) {
+            return Err(anyhow::anyhow!(
+                "--output-format lists {} twice, each format is one file",
+                format.extension()
+            ));
+        }
+    }
+    if json_answer && formats.contains(&OutputFormat::Txt) {
+        return Err(anyhow::anyhow!(
+            "--output-format txt strips Markdown, it would mangle --format-schema answers"
+        ));
+    }
+    Ok(())
+}
+
+/// Drop the Markdown markup the prompt asks for: headings, emphasis, inline
+/// code and the <image> tags around figure descriptions.
+fn plain_text(content: &str) -> String {
+    content
+        .lines()
+        .filter(|line| !matches!(line.trim(), "<image>" | "</image>" | "```"))
+        .map(|line| {
+            let line = if line.starts_with('#') {
+                line.trim_start_matches('#').trim_start()
+            } else {
+                line
+            };
+            line.replace("**", "")
+                .replace("__", "")
+                .replace('`', "")
+                .replace("<image>", "")
+                .replace("</image>", "")
+        })
+        .collect::<Vec<_>>()
+        .join("\n")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn atomic_write_replaces_the_file() {
+        let path =
+            std::env::temp_dir().join(format!("pdftopng-atomic-{}.json", std::process::id()));
+        write_atomic(&path, "old").unwrap();
+        write_atomic(&path, "new").unwrap();
+        assert_eq!(std::fs::read_to_string(&path).unwrap(), "new");
+        assert!(!path.with_extension("json.tmp").exists());
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn json_wraps_text_answers_as_a_string() {
+        let json = OutputFormat::Json.render("# Title\n{not json}", 3, false);
+        let value: serde_json::Value = serde_json::from_str(&json).unwrap();
+        assert_eq!(
+            value,
+            serde_json::json!({"page": 3, "content": "# Title\n{not json}"})
+        );
+    }
+
+    #[test]
+    fn json_embeds_schema_answers_parsed() {
+        let json = OutputFormat::Json.render(r#"{"title": "A", "lines": ["x"]}"#, 2, true);
+        let value: serde_json::Value = serde_json::from_str(&json).unwrap();
+        assert_eq!(
+            value,
+            serde_json::json!({"page": 2, "content": {"title": "A", "lines": ["x"]}})
+        );
+    }
+
+    #[test]
+    fn txt_drops_markdown() {
+        assert_eq!(
+            OutputFormat::Txt.render("## Head\n**bold** `code`\n<image>\nfig\n</image>", 1, false),
+            "Head\nbold code\nfig"
+        );
+    }
+
+    #[test]
+    fn utc_dates() {
+        assert_eq!(utc_date(0), "1970-01-01");
+        assert_eq!(utc_date(951_782_400), "2000-02-29");
+        assert_eq!(utc_date(1_704_067_199), "2023-12-31");
+        assert_eq!(utc_date(1_704_067_200), "2024-01-01");
+    }
+
+    #[test]
+    fn summary_footer_lists_pages_and_totals() {
+        let rows = [
+            SummaryRow {
+                page_no: 1,
+                model: Some("llava"),
+                tokens: 120,
+            },
+            SummaryRow {
+                page_no: 2,
+                model: None,
+                tokens: 0,
+            },
+            SummaryRow {
+                page_no: 3,
+                model: Some("qwen2.5vl"),
+                tokens: 80,
+            },
+        ];
+        assert_eq!(
+            summary_footer(&rows, 1_704_067_200),
+            "## Summary\n\n\
+             - Pages: 3 (1 from an earlier run)\n\
+             - Model: llava, qwen2.5vl\n\
+             - Date: 2024-01-01\n\n\
+             | Page | Model | Tokens |\n|---:|---|---:|\n\
+             | 1 | llava | 120 |\n\
+             | 2 | - | - |\n\
+             | 3 | qwen2.5vl | 80 |\n\
+             | Total | | 200 |\n"
+        );
+    }
+
+    #[test]
+    fn checks_format_combinations() {
+        use OutputFormat::*;
+        assert!(check(&[Md, Json, Txt], false).is_ok());
+        assert!(check(&[Md, Json], true).is_ok());
+        assert!(check(&[Md, Txt], true).is_err());
+        assert!(check(&[Json, Md, Json], false).is_err());
+    }
+}
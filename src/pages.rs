@@ -0,0 +1,166 @@
+use anyhow::Result;
+use clap::ValueEnum;
+use pdfium_render::prelude::*;
+use std::collections::BTreeSet;
+
+use crate::postprocess;
+
+/// Order pages are dispatched in. Output files are named after the page
+/// number whatever the order.
+#[derive(ValueEnum, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PageOrder {
+    /// first page to last
+    Forward,
+    /// last page to first
+    Reverse,
+    /// by printed page label: roman front matter, then arabic numbers, then
+    /// other labels alphabetically, then unlabelled pages
+    Label,
+}
+
+/// A parsed `--pages` list, kept as inclusive ranges so "1-999999999"
+/// costs nothing until it is checked against a document.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PageSpec {
+    /// first and last page of each item, with the item as written
+    ranges: Vec<(usize, usize, String)>,
+}
+
+impl PageSpec {
+    /// The items, as written, that go past a document of `page_count` pages.
+    pub fn beyond(&self, page_count: usize) -> Vec<&str> {
+        self.ranges
+            .iter()
+            .filter(|(_, last, _)| *last > page_count)
+            .map(|(_, _, item)| item.as_str())
+            .collect()
+    }
+
+    /// The page numbers named, cut to a document of `page_count` pages.
+    pub fn pages(&self, page_count: usize) -> BTreeSet<usize> {
+        self.ranges
+            .iter()
+            .flat_map(|&(first, last, _)| first..=last.min(page_count))
+            .collect()
+    }
+}
+
+/// Parse a `--pages` list such as "1,4,7-12,20" into the ranges of page
+/// numbers (1-based) it names.
+pub fn parse_page_spec(s: &str) -> Result<PageSpec> {
+    let number = |text: &str| {
+        text.trim()
+            .parse::<usize>()
+            .ok()
+            .filter(|page| *page > 0)
+            .ok_or_else(|| anyhow::anyhow!("Invalid page {:?} in --pages {:?}", text.trim(), s))
+    };
+    let mut ranges = Vec::new();
+    for item in s.split(',') {
+        match item.split_once('-') {
+            Some((first, last)) => {
+                let (first, last) = (number(first)?, number(last)?);
+                if first > last {
+                    return Err(anyhow::anyhow!(
+                        "Reversed range {}-{} in --pages {:?}",
+                        first,
+                        last,
+                        s
+                    ));
+                }
+                ranges.push((first, last, item.trim().to_string()));
+            }
+            None => {
+                let page = number(item)?;
+                ranges.push((page, page, item.trim().to_string()));
+            }
+        }
+    }
+    Ok(PageSpec { ranges })
+}
+
+/// Page numbers (1-based) of `selected`, sorted, in `order`.
+pub fn ordered(pages: &PdfPages, selected: Vec<usize>, order: PageOrder) -> Vec<usize> {
+    let mut page_numbers = selected;
+    match order {
+        PageOrder::Forward => {}
+        PageOrder::Reverse => page_numbers.reverse(),
+        PageOrder::Label => {
+            // stable, so equal labels keep their page order
+            page_numbers.sort_by_cached_key(|page_no| {
+                let label = pages
+                    .get((page_no - 1) as PdfPageIndex)
+                    .ok()
+                    .and_then(|page| page.label().map(str::to_string));
+                label_key(label)
+            });
+        }
+    }
+    page_numbers
+}
+
+fn label_key(label: Option<String>) -> (u8, usize, String) {
+    let Some(label) = label else {
+        return (3, 0, String::new());
+    };
+    match postprocess::page_number(&label) {
+        Some(number) if label.trim().chars().all(|c| c.is_ascii_digit()) => {
+            (1, number, String::new())
+        }
+        Some(number) => (0, number, String::new()),
+        None => (2, 0, label),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_pages_and_ranges() {
+        let spec = parse_page_spec("1, 4,7-9,20").unwrap();
+        assert_eq!(
+            spec.pages(100).into_iter().collect::<Vec<_>>(),
+            [1, 4, 7, 8, 9, 20]
+        );
+    }
+
+    #[test]
+    fn overlapping_ranges_are_merged() {
+        let spec = parse_page_spec("3-6,5-8,6").unwrap();
+        assert_eq!(
+            spec.pages(100).into_iter().collect::<Vec<_>>(),
+            [3, 4, 5, 6, 7, 8]
+        );
+    }
+
+    #[test]
+    fn rejects_reversed_ranges() {
+        let err = parse_page_spec("1,5-3").unwrap_err();
+        assert!(err.to_string().contains("Reversed range 5-3"), "{}", err);
+    }
+
+    #[test]
+    fn rejects_zero_and_garbage() {
+        assert!(parse_page_spec("0").is_err());
+        assert!(parse_page_spec("0-4").is_err());
+        assert!(parse_page_spec("1,,2").is_err());
+        assert!(parse_page_spec("a-3").is_err());
+        assert!(parse_page_spec("").is_err());
+    }
+
+    #[test]
+    fn reports_items_beyond_the_document_as_written() {
+        let spec = parse_page_spec("1,8, 10-12,3-9").unwrap();
+        assert_eq!(spec.beyond(9), ["10-12"]);
+        assert_eq!(spec.beyond(7), ["8", "10-12", "3-9"]);
+        assert!(spec.beyond(12).is_empty());
+    }
+
+    #[test]
+    fn huge_ranges_are_cut_to_the_document() {
+        let spec = parse_page_spec(&format!("2-{}", usize::MAX)).unwrap();
+        assert_eq!(spec.beyond(3), [format!("2-{}", usize::MAX)]);
+        assert_eq!(spec.pages(3).into_iter().collect::<Vec<_>>(), [2, 3]);
+    }
+}
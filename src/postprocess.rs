@@ -0,0 +1,293 @@
+/// How far a printed page number may stray from the page index (front
+/// matter, unnumbered plates) and still be taken for one.
+const PAGE_NUMBER_SLACK: usize = 30;
+
+/// Responses longer than this are transcriptions, even if they contain a
+/// refusal phrase.
+const REFUSAL_MAX_LEN: usize = 400;
+
+/// Chatter models wrap transcriptions in despite the prompt. A line starting
+/// with one of these, ignoring case, is dropped from either end of a response.
+pub const DEFAULT_BOILERPLATE: &[&str] = &[
+    "here is the transcription",
+    "here's the transcription",
+    "here is the text",
+    "here's the text",
+    "below is the transcription",
+    "sure, here",
+    "sure! here",
+    "certainly, here",
+    "certainly! here",
+    "let me know if",
+    "i hope this helps",
+    "if you need anything else",
+    "feel free to ask",
+];
+
+/// Phrases safety-tuned models answer with instead of a transcription.
+pub const DEFAULT_REFUSAL_PATTERNS: &[&str] = &[
+    "i can't transcribe",
+    "i cannot transcribe",
+    "i can't help with",
+    "i cannot help with",
+    "i can't assist",
+    "i cannot assist",
+    "i'm unable to",
+    "i am unable to",
+    "i'm not able to",
+    "i am not able to",
+    "i'm sorry, but",
+    // not "copyrighted" alone, short imprint pages say it too
+    "reproduce copyrighted",
+];
+
+/// Whether `text` is a short answer containing one of `patterns`, ignoring
+/// case: a refusal rather than a transcription.
+pub fn is_refusal(text: &str, patterns: &[String]) -> bool {
+    let text = text.trim();
+    if text.len() > REFUSAL_MAX_LEN {
+        return false;
+    }
+    let text = text.to_lowercase();
+    patterns
+        .iter()
+        .any(|pattern| text.contains(&pattern.to_lowercase()))
+}
+
+/// Remove whole lines starting with one of `phrases` from the start and the
+/// end of `text`, skipping blank lines. Text in between is never touched.
+pub fn strip_boilerplate(text: &str, phrases: &[String]) -> String {
+    let phrases = phrases
+        .iter()
+        .map(|phrase| phrase.to_lowercase())
+        .collect::<Vec<_>>();
+    let is_boilerplate = |line: &str| {
+        let line = line.trim().to_lowercase();
+        line.is_empty() || phrases.iter().any(|phrase| line.starts_with(phrase))
+    };
+
+    let lines = text.lines().collect::<Vec<_>>();
+    let Some(first) = lines.iter().position(|line| !is_boilerplate(line)) else {
+        return String::new();
+    };
+    let last = lines
+        .iter()
+        .rposition(|line| !is_boilerplate(line))
+        .unwrap_or(first);
+    lines[first..=last].join("\n")
+}
+
+/// Share of words two texts have in common (Jaccard index over their word
+/// sets, ignoring case and punctuation), from 0.0 to 1.0.
+pub fn similarity(a: &str, b: &str) -> f64 {
+    let words = |text: &str| {
+        text.split(|c: char| !c.is_alphanumeric())
+            .filter(|word| !word.is_empty())
+            .map(str::to_lowercase)
+            .collect::<std::collections::HashSet<_>>()
+    };
+    let (a, b) = (words(a), words(b));
+    let union = a.union(&b).count();
+    if union == 0 {
+        return 1.0;
+    }
+    a.intersection(&b).count() as f64 / union as f64
+}
+
+/// Value of a line holding nothing but a page number, arabic or roman,
+/// optionally dashed ("- 12 -").
+pub fn page_number(line: &str) -> Option<usize> {
+    let token = line
+        .trim()
+        .trim_matches(|c: char| c == '-' || c == '–' || c == '—' || c.is_whitespace());
+    if token.is_empty() || token.len() > 8 {
+        return None;
+    }
+    if token.chars().all(|c| c.is_ascii_digit()) {
+        return token.parse().ok();
+    }
+    roman_value(token)
+}
+
+/// Value of a roman numeral from 1 to 399 in its canonical spelling ("iv",
+/// not "iiii"), so words made of numeral letters such as "civil" are not
+/// taken for one.
+fn roman_value(token: &str) -> Option<usize> {
+    let digit = |c: char| match c.to_ascii_lowercase() {
+        'i' => Some(1),
+        'v' => Some(5),
+        'x' => Some(10),
+        'l' => Some(50),
+        'c' => Some(100),
+        _ => None,
+    };
+    // all lower or all upper case, so names like "Li" or "Xi" never match
+    if !(token.chars().all(|c| c.is_ascii_lowercase())
+        || token.chars().all(|c| c.is_ascii_uppercase()))
+    {
+        return None;
+    }
+    let values = token.chars().map(digit).collect::<Option<Vec<usize>>>()?;
+    let mut total = 0;
+    for (i, value) in values.iter().enumerate() {
+        match values.get(i + 1) {
+            Some(next) if next > value => total -= *value as isize,
+            _ => total += *value as isize,
+        }
+    }
+    usize::try_from(total)
+        .ok()
+        .filter(|total| roman(*total).is_some_and(|roman| roman.eq_ignore_ascii_case(token)))
+}
+
+/// Canonical lower case roman numeral for 1 to 399.
+fn roman(mut value: usize) -> Option<String> {
+    const NUMERALS: &[(usize, &str)] = &[
+        (100, "c"),
+        (90, "xc"),
+        (50, "l"),
+        (40, "xl"),
+        (10, "x"),
+        (9, "ix"),
+        (5, "v"),
+        (4, "iv"),
+        (1, "i"),
+    ];
+    if !(1..400).contains(&value) {
+        return None;
+    }
+    let mut out = String::new();
+    for &(step, numeral) in NUMERALS {
+        while value >= step {
+            out += numeral;
+            value -= step;
+        }
+    }
+    Some(out)
+}
+
+/// Remove a lone page number line from the start and from the end of a
+/// page's text. The number must be the page's label or within a few pages
+/// of its index, so numbers belonging to the content are left alone.
+pub fn strip_page_numbers(text: &str, page_no: usize, label: Option<&str>) -> String {
+    let label = label.and_then(page_number);
+    let is_page_number = |line: &str| {
+        page_number(line).is_some_and(|number| {
+            Some(number) == label || number.abs_diff(page_no) <= PAGE_NUMBER_SLACK
+        })
+    };
+
+    let mut lines = text.lines().collect::<Vec<_>>();
+    if let Some(first) = lines.iter().position(|line| !line.trim().is_empty())
+        && is_page_number(lines[first])
+    {
+        lines.remove(first);
+    }
+    if let Some(last) = lines.iter().rposition(|line| !line.trim().is_empty())
+        && is_page_number(lines[last])
+    {
+        lines.remove(last);
+    }
+    lines.join("\n").trim_matches('\n').to_string()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn defaults(list: &[&str]) -> Vec<String> {
+        list.iter().map(|phrase| phrase.to_string()).collect()
+    }
+
+    #[test]
+    fn recognizes_refusals() {
+        let patterns = defaults(DEFAULT_REFUSAL_PATTERNS);
+        assert!(is_refusal(
+            "I'm sorry, but I can't help with that.",
+            &patterns
+        ));
+        assert!(is_refusal(
+            "I won't reproduce copyrighted material.",
+            &patterns
+        ));
+        assert!(!is_refusal(
+            "This book is copyrighted material. All rights reserved.\n\nPrinted in France",
+            &patterns
+        ));
+        // a long answer is a transcription, whatever it quotes
+        let long = format!("I cannot transcribe {}", "word ".repeat(100));
+        assert!(!is_refusal(&long, &patterns));
+    }
+
+    #[test]
+    fn strips_boilerplate_at_both_ends() {
+        let text =
+            "Sure, here is the text:\n\n# Title\nBody line\n\nLet me know if you need more.\n";
+        assert_eq!(
+            strip_boilerplate(text, &defaults(DEFAULT_BOILERPLATE)),
+            "# Title\nBody line"
+        );
+    }
+
+    #[test]
+    fn keeps_boilerplate_phrases_inside_the_text() {
+        let text = "First line\nHere is the text the author quoted.\nLast line";
+        assert_eq!(
+            strip_boilerplate(text, &defaults(DEFAULT_BOILERPLATE)),
+            text
+        );
+    }
+
+    #[test]
+    fn boilerplate_only_answer_becomes_empty() {
+        let text = "HERE'S THE TRANSCRIPTION\n\nI hope this helps!";
+        assert_eq!(strip_boilerplate(text, &defaults(DEFAULT_BOILERPLATE)), "");
+    }
+
+    #[test]
+    fn custom_boilerplate_phrases_ignore_case() {
+        let text = "Transcript follows\nContent";
+        assert_eq!(
+            strip_boilerplate(text, &defaults(&["TRANSCRIPT FOLLOWS"])),
+            "Content"
+        );
+    }
+
+    #[test]
+    fn reads_canonical_roman_numerals() {
+        assert_eq!(page_number("iv"), Some(4));
+        assert_eq!(page_number("XIV"), Some(14));
+        assert_eq!(page_number("- xcix -"), Some(99));
+        assert_eq!(page_number("cccxcix"), Some(399));
+    }
+
+    #[test]
+    fn rejects_non_canonical_roman_numerals() {
+        for token in [
+            "iiii", "vx", "il", "ic", "xxxx", "civil", "lil", "Mix", "Iv",
+        ] {
+            assert_eq!(page_number(token), None, "{}", token);
+        }
+    }
+
+    #[test]
+    fn strips_page_numbers_near_the_page_index() {
+        assert_eq!(
+            strip_page_numbers("12\n\nText\n\n- 12 -\n", 12, None),
+            "Text"
+        );
+        assert_eq!(strip_page_numbers("Text\nxii", 40, Some("xii")), "Text");
+        assert_eq!(strip_page_numbers("Text\nvii", 7, None), "Text");
+    }
+
+    #[test]
+    fn keeps_numbers_belonging_to_the_content() {
+        assert_eq!(strip_page_numbers("1999\nText", 12, None), "1999\nText");
+        assert_eq!(
+            strip_page_numbers("Text\n12\nmore", 12, None),
+            "Text\n12\nmore"
+        );
+        assert_eq!(strip_page_numbers("Text\nciv", 104, None), "Text");
+        assert_eq!(strip_page_numbers("Text\ncivil", 153, None), "Text\ncivil");
+    }
+}
@@ -0,0 +1,458 @@
+use anyhow::Result;
+use clap::ValueEnum;
+use pdfium_render::prelude::*;
+use std::{io::Cursor, path::Path};
+
+/// Minimum share of the page area an image must cover to count as a scan.
+const FULL_PAGE_COVERAGE: f32 = 0.9;
+
+/// Bind to the pdfium library at `path` (the library file itself or the
+/// directory holding it), or like `Pdfium::default()` does when unset: the
+/// current directory first, then the system library. Unlike the default, a
+/// missing library is reported as an error explaining how to fix it.
+pub fn bind_pdfium(path: Option<&str>) -> Result<Pdfium> {
+    let bindings = match path {
+        Some(path) if Path::new(path).is_dir() => {
+            Pdfium::bind_to_library(Pdfium::pdfium_platform_library_name_at_path(path))
+        }
+        Some(path) => Pdfium::bind_to_library(path),
+        None => Pdfium::bind_to_library(Pdfium::pdfium_platform_library_name_at_path("./"))
+            .or_else(|_| Pdfium::bind_to_system_library()),
+    };
+
+    bindings.map(Pdfium::new).map_err(|err| {
+        anyhow::anyhow!(
+            "Could not load the pdfium library ({:?}): {}\n\n\
+             pdftopng-rs needs a pdfium shared library ({}) at runtime. Download a build \
+             for your platform from https://github.com/bblanchon/pdfium-binaries, then \
+             either place it in the current directory or the system library path, or \
+             point --pdfium-path / PDFIUM_PATH at the file or its directory.",
+            path.unwrap_or("current directory, then system"),
+            err,
+            Pdfium::pdfium_platform_library_name().to_string_lossy()
+        )
+    })
+}
+
+/// If the page is essentially one full-page raster (a scanned page, possibly
+/// with an invisible OCR text layer on top), return that image's native
+/// pixel width.
+pub fn native_raster_width(page: &PdfPage) -> Option<u16> {
+    let mut images = page
+        .objects()
+        .iter()
+        .filter(|object| object.object_type() == PdfPageObjectType::Image);
+    let image = images.next()?;
+    if images.next().is_some() {
+        return None;
+    }
+
+    let bounds = image.bounds().ok()?;
+    let page_area = page.width().value * page.height().value;
+    let image_area = bounds.width().value * bounds.height().value;
+    if page_area <= 0.0 || image_area / page_area < FULL_PAGE_COVERAGE {
+        return None;
+    }
+
+    let width = image.as_image_object()?.width().ok()?;
+    u16::try_from(width).ok().filter(|width| *width > 0)
+}
+
+/// Every image object on the page, at its native resolution, encoded as PNG.
+/// Images that pdfium cannot decode are skipped.
+pub fn figure_images(page: &PdfPage) -> Vec<Vec<u8>> {
+    let mut figures = Vec::new();
+    for object in page.objects().iter() {
+        let Some(image) = object.as_image_object() else {
+            continue;
+        };
+        let Ok(image) = image.get_raw_image() else {
+            continue;
+        };
+        let rgba = image.to_rgba8();
+
+        let mut buffer = Vec::new();
+        let mut encoder = png::Encoder::new(&mut buffer, rgba.width(), rgba.height());
+        encoder.set_color(png::ColorType::Rgba);
+        encoder.set_depth(png::BitDepth::Eight);
+        let encoded = encoder.write_header().and_then(|mut writer| {
+            writer.write_image_data(rgba.as_raw())?;
+            writer.finish()
+        });
+        if encoded.is_ok() {
+            figures.push(buffer);
+        }
+    }
+    figures
+}
+
+/// Luma of RGBA pixels (BT.601 weights), transparency flattened onto white.
+/// Works on 8-bit pixels and on the big-endian 16-bit ones of
+/// `downsample_to_16`, keeping their depth.
+pub fn grayscale(pixels: &[u8], depth: BitDepth) -> Vec<u8> {
+    let luma = |rgba: [u32; 4], max: u32| {
+        let over_white = |channel: u32| (channel * rgba[3] + max * (max - rgba[3]) + max / 2) / max;
+        (299 * over_white(rgba[0]) + 587 * over_white(rgba[1]) + 114 * over_white(rgba[2]) + 500)
+            / 1000
+    };
+    match depth {
+        BitDepth::Eight => pixels
+            .chunks_exact(4)
+            .map(|pixel| luma([0, 1, 2, 3].map(|channel| pixel[channel] as u32), 255) as u8)
+            .collect(),
+        BitDepth::Sixteen => pixels
+            .chunks_exact(8)
+            .flat_map(|pixel| {
+                let rgba = [0, 1, 2, 3].map(|channel| {
+                    u16::from_be_bytes([pixel[2 * channel], pixel[2 * channel + 1]]) as u32
+                });
+                (luma(rgba, 65535) as u16).to_be_bytes()
+            })
+            .collect(),
+    }
+}
+
+/// Codec of the page images written to disk and sent to the model.
+#[derive(ValueEnum, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ImageFormat {
+    /// lossless, 8 or 16 bits per channel
+    Png,
+    /// lossy, much smaller for scanned pages
+    Jpeg,
+    /// lossless like PNG, usually smaller
+    Webp,
+}
+
+impl ImageFormat {
+    pub fn extension(self) -> &'static str {
+        match self {
+            ImageFormat::Png => "png",
+            ImageFormat::Jpeg => "jpg",
+            ImageFormat::Webp => "webp",
+        }
+    }
+}
+
+/// Encode 8-bit RGBA pixels, or luma ones from `grayscale`, as a JPEG of
+/// the given quality (0 to 100, 0 encoded as 1), flattening transparency
+/// onto white.
+pub fn encode_jpeg(
+    pixels: &[u8],
+    width: u32,
+    height: u32,
+    quality: u8,
+    gray: bool,
+) -> Result<Vec<u8>> {
+    let mut buffer = Vec::new();
+    let mut encoder =
+        image::codecs::jpeg::JpegEncoder::new_with_quality(&mut buffer, quality.clamp(1, 100));
+    if gray {
+        encoder.encode(pixels, width, height, image::ExtendedColorType::L8)?;
+        return Ok(buffer);
+    }
+
+    let rgb = pixels
+        .chunks_exact(4)
+        .flat_map(|pixel| {
+            let alpha = pixel[3] as u32;
+            let over_white = move |channel: u8| {
+                ((channel as u32 * alpha + 255 * (255 - alpha) + 127) / 255) as u8
+            };
+            [
+                over_white(pixel[0]),
+                over_white(pixel[1]),
+                over_white(pixel[2]),
+            ]
+        })
+        .collect::<Vec<_>>();
+    encoder.encode(&rgb, width, height, image::ExtendedColorType::Rgb8)?;
+    Ok(buffer)
+}
+
+/// Encode 8-bit RGBA pixels, or luma ones from `grayscale`, as a lossless
+/// WebP (the encoder has no lossy mode).
+pub fn encode_webp(pixels: &[u8], width: u32, height: u32, gray: bool) -> Result<Vec<u8>> {
+    let mut buffer = Vec::new();
+    let color = if gray {
+        image::ExtendedColorType::L8
+    } else {
+        image::ExtendedColorType::Rgba8
+    };
+    image::codecs::webp::WebPEncoder::new_lossless(&mut buffer)
+        .encode(pixels, width, height, color)?;
+    Ok(buffer)
+}
+
+/// Page region to keep, as fractions of the page size measured from the
+/// top-left corner.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Crop {
+    pub left: f32,
+    pub top: f32,
+    pub right: f32,
+    pub bottom: f32,
+}
+
+/// Union of the text objects' bounds, grown by `padding` points on each side.
+/// None when the page has no text layer.
+pub fn text_crop(page: &PdfPage, padding: f32) -> Option<Crop> {
+    objects_crop(page, padding, |object| {
+        object.object_type() == PdfPageObjectType::Text
+    })
+}
+
+/// Union of every page object's bounds (text, paths, images...), grown by
+/// `padding` points on each side. None when the page is empty.
+pub fn content_crop(page: &PdfPage, padding: f32) -> Option<Crop> {
+    objects_crop(page, padding, |_| true)
+}
+
+fn objects_crop(
+    page: &PdfPage,
+    padding: f32,
+    keep: impl Fn(&PdfPageObject) -> bool,
+) -> Option<Crop> {
+    let page_width = page.width().value;
+    let page_height = page.height().value;
+    if page_width <= 0.0 || page_height <= 0.0 {
+        return None;
+    }
+
+    // pdf coordinates, origin at the bottom-left corner
+    let mut union: Option<(f32, f32, f32, f32)> = None;
+    for object in page.objects().iter().filter(|object| keep(object)) {
+        let Ok(bounds) = object.bounds() else {
+            continue;
+        };
+        let (left, bottom, right, top) = (
+            bounds.left().value,
+            bounds.bottom().value,
+            bounds.right().value,
+            bounds.top().value,
+        );
+        union = Some(match union {
+            Some((l, b, r, t)) => (l.min(left), b.min(bottom), r.max(right), t.max(top)),
+            None => (left, bottom, right, top),
+        });
+    }
+    let (left, bottom, right, top) = union?;
+
+    let crop = Crop {
+        left: ((left - padding) / page_width).clamp(0.0, 1.0),
+        top: ((page_height - top - padding) / page_height).clamp(0.0, 1.0),
+        right: ((right + padding) / page_width).clamp(0.0, 1.0),
+        bottom: ((page_height - bottom + padding) / page_height).clamp(0.0, 1.0),
+    };
+    (crop.right > crop.left && crop.bottom > crop.top).then_some(crop)
+}
+
+/// Cut `crop` out of an RGBA8 buffer, returning the new size and pixels.
+pub fn crop_rgba(pixels: &[u8], width: u32, height: u32, crop: Crop) -> (u32, u32, Vec<u8>) {
+    let x0 = ((crop.left * width as f32) as usize).min(width as usize - 1);
+    let x1 = ((crop.right * width as f32).ceil() as usize).clamp(x0 + 1, width as usize);
+    let y0 = ((crop.top * height as f32) as usize).min(height as usize - 1);
+    let y1 = ((crop.bottom * height as f32).ceil() as usize).clamp(y0 + 1, height as usize);
+
+    let row_len = width as usize * 4;
+    let mut cropped = Vec::with_capacity((x1 - x0) * (y1 - y0) * 4);
+    for row in pixels.chunks(row_len).skip(y0).take(y1 - y0) {
+        cropped.extend_from_slice(&row[x0 * 4..x1 * 4]);
+    }
+    ((x1 - x0) as u32, (y1 - y0) as u32, cropped)
+}
+
+/// Bit depth of the PNG written to disk. Models get 8-bit images either way.
+#[derive(ValueEnum, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BitDepth {
+    #[value(name = "8")]
+    Eight,
+    /// render at twice the width and average 2x2 blocks, about 4x the
+    /// render time and 2x the file size
+    #[value(name = "16")]
+    Sixteen,
+}
+
+/// Average each 2x2 block of an RGBA8 buffer into one RGBA16 pixel, big
+/// endian as PNG stores it. The sums keep the intermediate tones (edges,
+/// gradients) that rendering straight at the final size rounds away.
+pub fn downsample_to_16(pixels: &[u8], width: u32, height: u32) -> (u32, u32, Vec<u8>) {
+    let (out_width, out_height) = ((width / 2).max(1), (height / 2).max(1));
+    let row_len = width as usize * 4;
+    let mut out = Vec::with_capacity(out_width as usize * out_height as usize * 8);
+    for y in 0..out_height as usize {
+        let rows = [2 * y, (2 * y + 1).min(height as usize - 1)];
+        for x in 0..out_width as usize {
+            let columns = [2 * x, (2 * x + 1).min(width as usize - 1)];
+            for channel in 0..4 {
+                let mut sum = 0u32;
+                for row in rows {
+                    for column in columns {
+                        sum += pixels[row * row_len + column * 4 + channel] as u32;
+                    }
+                }
+                // 4 * 255 maps to 65535
+                let value = (sum * 65535 + 510) / 1020;
+                out.extend_from_slice(&(value as u16).to_be_bytes());
+            }
+        }
+    }
+    (out_width, out_height, out)
+}
+
+/// Mean and standard deviation of the luminance of 8-bit RGBA pixels, both
+/// from 0.0 (black) to 1.0 (white). Transparent pixels count as paper.
+pub fn luminance_stats(rgba: &[u8]) -> (f64, f64) {
+    let gray = grayscale(rgba, BitDepth::Eight);
+    if gray.is_empty() {
+        return (1.0, 0.0);
+    }
+    let (sum, squares) = gray.iter().fold((0u64, 0u64), |(sum, squares), &value| {
+        let value = value as u64;
+        (sum + value, squares + value * value)
+    });
+    let count = gray.len() as f64;
+    let mean = sum as f64 / count;
+    let variance = (squares as f64 / count - mean * mean).max(0.0);
+    (mean / 255.0, variance.sqrt() / 255.0)
+}
+
+/// Whether a page with these `luminance_stats` is blank: white but for
+/// `threshold` on average, or uniform to within `threshold` (a tinted or
+/// black separator sheet).
+pub fn is_blank((mean, std_dev): (f64, f64), threshold: f64) -> bool {
+    mean >= 1.0 - threshold || std_dev <= threshold
+}
+
+/// The 8-bit RGBA pixels of an encoded PNG, JPEG or WebP image.
+pub fn decode_rgba(encoded: &[u8]) -> Result<Vec<u8>> {
+    if !encoded.starts_with(b"\x89PNG") {
+        return Ok(image::load_from_memory(encoded)?.to_rgba8().into_raw());
+    }
+    let mut decoder = png::Decoder::new(Cursor::new(encoded));
+    decoder.set_transformations(png::Transformations::normalize_to_color8());
+    let mut reader = decoder.read_info()?;
+    let mut pixels = vec![0; reader.output_buffer_size().unwrap_or_default()];
+    let info = reader.next_frame(&mut pixels)?;
+    pixels.truncate(info.buffer_size());
+    Ok(match info.color_type {
+        png::ColorType::Grayscale => pixels.iter().flat_map(|&v| [v, v, v, 255]).collect(),
+        png::ColorType::GrayscaleAlpha => pixels
+            .chunks_exact(2)
+            .flat_map(|pixel| [pixel[0], pixel[0], pixel[0], pixel[1]])
+            .collect(),
+        png::ColorType::Rgb => pixels
+            .chunks_exact(3)
+            .flat_map(|pixel| [pixel[0], pixel[1], pixel[2], 255])
+            .collect(),
+        _ => pixels,
+    })
+}
+
+/// Re-encode a PNG of any bit depth as 8 bits per channel.
+pub fn eight_bit_png(buffer: &[u8]) -> Result<Vec<u8>> {
+    let mut decoder = png::Decoder::new(Cursor::new(buffer));
+    decoder.set_transformations(png::Transformations::STRIP_16);
+    let mut reader = decoder.read_info()?;
+    let mut pixels = vec![0; reader.output_buffer_size().unwrap_or_default()];
+    let info = reader.next_frame(&mut pixels)?;
+
+    let mut encoded = Vec::new();
+    let mut encoder = png::Encoder::new(&mut encoded, info.width, info.height);
+    encoder.set_color(info.color_type);
+    encoder.set_depth(png::BitDepth::Eight);
+    let mut writer = encoder.write_header()?;
+    writer.write_image_data(&pixels[..info.buffer_size()])?;
+    writer.finish()?;
+    Ok(encoded)
+}
+
+/// Width and height of an encoded PNG, JPEG or WebP image. None when its
+/// header cannot be read.
+pub fn image_size(encoded: &[u8]) -> Option<(u32, u32)> {
+    use image::ImageDecoder;
+    if encoded.starts_with(b"\x89PNG") {
+        let info = png::Decoder::new(Cursor::new(encoded)).read_info().ok()?;
+        Some((info.info().width, info.info().height))
+    } else if encoded.starts_with(b"RIFF") {
+        let decoder = image::codecs::webp::WebPDecoder::new(Cursor::new(encoded)).ok()?;
+        Some(decoder.dimensions())
+    } else {
+        let decoder = image::codecs::jpeg::JpegDecoder::new(Cursor::new(encoded)).ok()?;
+        Some(decoder.dimensions())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn grayscale_weighs_channels_and_flattens_onto_white() {
+        let pixels = [
+            255, 255, 255, 255, // white
+            0, 0, 0, 255, // black
+            255, 0, 0, 255, // red
+            0, 0, 0, 0, // transparent
+        ];
+        assert_eq!(grayscale(&pixels, BitDepth::Eight), [255, 0, 76, 255]);
+    }
+
+    #[test]
+    fn grayscale_keeps_sixteen_bit_depth() {
+        let pixels = [
+            0xff, 0xff, 0, 0, 0, 0, 0xff, 0xff, 0, 0, 0, 0, 0, 0, 0xff, 0xff,
+        ];
+        let gray = grayscale(&pixels, BitDepth::Sixteen);
+        assert_eq!(gray.len(), 4);
+        assert_eq!(u16::from_be_bytes([gray[0], gray[1]]), 19595);
+        assert_eq!(gray[2..], [0, 0]);
+    }
+
+    #[test]
+    fn encodes_webp_and_reads_its_size() {
+        let rgba = [255, 0, 0, 255].repeat(30 * 60);
+        let webp = encode_webp(&rgba, 30, 60, false).unwrap();
+        assert!(webp.starts_with(b"RIFF") && &webp[8..12] == b"WEBP");
+        assert_eq!(image_size(&webp), Some((30, 60)));
+
+        let gray = grayscale(&rgba, BitDepth::Eight);
+        assert!(encode_webp(&gray, 30, 60, true).is_ok());
+    }
+
+    #[test]
+    fn blank_pages_are_white_or_uniform() {
+        let white = [255, 255, 255, 255].repeat(1000);
+        assert!(is_blank(luminance_stats(&white), 0.004));
+        let black = [0, 0, 0, 255].repeat(1000);
+        assert!(is_blank(luminance_stats(&black), 0.004));
+
+        // a line of text on one page in fifty
+        let mut text = white.clone();
+        text[..80].fill(0);
+        text[..80]
+            .chunks_exact_mut(4)
+            .for_each(|pixel| pixel[3] = 255);
+        let (mean, std_dev) = luminance_stats(&text);
+        assert!((mean - 0.98).abs() < 1e-9);
+        assert!(std_dev > 0.1);
+        assert!(!is_blank((mean, std_dev), 0.004));
+        assert!(is_blank((mean, std_dev), 0.03));
+    }
+
+    #[test]
+    fn decodes_kept_images_to_rgba() {
+        let rgba = [10, 20, 30, 255].repeat(16 * 16);
+        let gray = grayscale(&rgba, BitDepth::Eight);
+        let webp = encode_webp(&gray, 16, 16, true).unwrap();
+        let decoded = decode_rgba(&webp).unwrap();
+        assert_eq!(decoded.len(), rgba.len());
+        assert_eq!(decoded[..4], [gray[0], gray[0], gray[0], 255]);
+    }
+
+    #[test]
+    fn jpeg_quality_zero_is_accepted() {
+        let rgba = [10, 20, 30, 255].repeat(16 * 16);
+        let jpeg = encode_jpeg(&rgba, 16, 16, 0, false).unwrap();
+        assert_eq!(image_size(&jpeg), Some((16, 16)));
+    }
+}
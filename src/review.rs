@@ -0,0 +1,246 @@
+use std::{
+    fmt::Write,
+    path::{Component, Path, PathBuf},
+};
+
+const STYLE: &str = "
+body { margin: 0; font-family: sans-serif; }
+section { display: flex; gap: 1em; padding: 1em; border-bottom: 1px solid #ccc; }
+section > img { width: 50%; height: auto; object-fit: contain; align-self: flex-start; }
+section > article { width: 50%; }
+article[contenteditable]:focus { outline: 2px solid #48c; }
+figure.description { margin: 0.5em 0; padding: 0.5em; background: #f4f4f4; }
+";
+
+/// One reviewed page: its image(s) and transcription.
+pub struct ReviewPage {
+    pub page_no: usize,
+    pub images: Vec<PathBuf>,
+    pub markdown: String,
+}
+
+/// A self-contained HTML page showing each page image next to its
+/// transcription, editable in the browser. Images are referenced relative
+/// to `html_path`, so the file and the images can be moved together.
+pub fn render(title: &str, html_path: &Path, pages: &[ReviewPage]) -> String {
+    let base = html_path.parent().unwrap_or(Path::new(""));
+    let mut out = String::new();
+    let _ = write!(
+        out,
+        "<!DOCTYPE html>\n<html>\n<head>\n<meta charset=\"utf-8\">\n<title>{}</title>\n<style>{}</style>\n</head>\n<body>\n",
+        escape(title),
+        STYLE
+    );
+    for page in pages {
+        let _ = writeln!(out, "<section id=\"page-{}\">", page.page_no);
+        for image in &page.images {
+            let _ = writeln!(
+                out,
+                "<img src=\"{}\" alt=\"page {}\">",
+                escape(
+                    &relative_to(image, base)
+                        .to_string_lossy()
+                        .replace('\\', "/")
+                ),
+                page.page_no
+            );
+        }
+        let _ = writeln!(
+            out,
+            "<article contenteditable=\"true\">\n{}</article>\n</section>",
+            markdown_to_html(&page.markdown)
+        );
+    }
+    out += "</body>\n</html>\n";
+    out
+}
+
+/// `path` relative to the directory `base`, both taken from the current
+/// directory when relative.
+fn relative_to(path: &Path, base: &Path) -> PathBuf {
+    let absolute = |path: &Path| {
+        std::env::current_dir()
+            .map(|dir| dir.join(path))
+            .unwrap_or_else(|_| path.to_path_buf())
+    };
+    let (path, base) = (absolute(path), absolute(base));
+    let path = path.components().collect::<Vec<_>>();
+    let base = base.components().collect::<Vec<_>>();
+    let common = path.iter().zip(&base).take_while(|(a, b)| a == b).count();
+
+    let mut relative = PathBuf::new();
+    for _ in &base[common..] {
+        relative.push(Component::ParentDir);
+    }
+    for component in &path[common..] {
+        relative.push(component);
+    }
+    relative
+}
+
+fn escape(text: &str) -> String {
+    text.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+}
+
+/// The subset of Markdown the transcription prompt asks for: headings,
+/// paragraphs, lists, emphasis, inline code and <image> descriptions.
+fn markdown_to_html(markdown: &str) -> String {
+    let mut out = String::new();
+    let mut paragraph = Vec::new();
+    let mut list: Option<&str> = None;
+
+    let flush_paragraph = |out: &mut String, paragraph: &mut Vec<String>| {
+        if !paragraph.is_empty() {
+            let _ = writeln!(out, "<p>{}</p>", paragraph.join("<br>\n"));
+            paragraph.clear();
+        }
+    };
+
+    for line in markdown.lines() {
+        let trimmed = line.trim();
+        let item = trimmed
+            .strip_prefix("- ")
+            .or_else(|| trimmed.strip_prefix("* "))
+            .or_else(|| trimmed.strip_prefix("+ "))
+            .map(|item| ("ul", item))
+            .or_else(|| {
+                let (number, item) = trimmed.split_once(". ")?;
+                number
+                    .chars()
+                    .all(|c| c.is_ascii_digit())
+                    .then_some(("ol", item))
+            });
+
+        if list.is_some() && item.map(|(tag, _)| tag) != list {
+            let _ = writeln!(out, "</{}>", list.take().unwrap_or_default());
+        }
+
+        if trimmed.is_empty() {
+            flush_paragraph(&mut out, &mut paragraph);
+        } else if trimmed == "<image>" {
+            flush_paragraph(&mut out, &mut paragraph);
+            out += "<figure class=\"description\">\n";
+        } else if trimmed == "</image>" {
+            flush_paragraph(&mut out, &mut paragraph);
+            out += "</figure>\n";
+        } else if let Some((tag, item)) = item {
+            flush_paragraph(&mut out, &mut paragraph);
+            if list.is_none() {
+                let _ = writeln!(out, "<{}>", tag);
+                list = Some(tag);
+            }
+            let _ = writeln!(out, "<li>{}</li>", inline(item));
+        } else if trimmed.starts_with('#') {
+            flush_paragraph(&mut out, &mut paragraph);
+            let level = trimmed.chars().take_while(|c| *c == '#').count().min(6);
+            let _ = writeln!(
+                out,
+                "<h{level}>{}</h{level}>",
+                inline(trimmed[level..].trim())
+            );
+        } else {
+            paragraph.push(inline(trimmed));
+        }
+    }
+    flush_paragraph(&mut out, &mut paragraph);
+    if let Some(tag) = list {
+        let _ = writeln!(out, "</{}>", tag);
+    }
+    out
+}
+
+/// Escape a line and turn **bold**, *italic* and `code` spans into tags.
+fn inline(text: &str) -> String {
+    let mut out = escape(text);
+    for (marker, tag) in [
+        ("`", "code"),
+        ("**", "strong"),
+        ("__", "strong"),
+        ("*", "em"),
+    ] {
+        let mut parts = out.split(marker).collect::<Vec<_>>();
+        // an unmatched marker stays as it is
+        let trailing = if parts.len() % 2 == 0 {
+            parts.pop()
+        } else {
+            None
+        };
+        let mut joined = String::new();
+        for (i, part) in parts.iter().enumerate() {
+            if i > 0 {
+                joined += &if i % 2 == 1 {
+                    format!("<{}>", tag)
+                } else {
+                    format!("</{}>", tag)
+                };
+            }
+            joined += part;
+        }
+        if let Some(trailing) = trailing {
+            joined += marker;
+            joined += trailing;
+        }
+        out = joined;
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn escapes_model_output() {
+        let html = markdown_to_html("# <b>Title</b>\n\n<script>alert(\"x\")</script>");
+        assert!(
+            !html.contains("<script>") && !html.contains("<b>"),
+            "{}",
+            html
+        );
+        assert!(html.contains("&lt;script&gt;alert(&quot;x&quot;)&lt;/script&gt;"));
+        assert!(html.contains("<h1>&lt;b&gt;Title&lt;/b&gt;</h1>"));
+    }
+
+    #[test]
+    fn converts_inline_markup() {
+        assert_eq!(
+            inline("**bold** and *it* `a<b`"),
+            "<strong>bold</strong> and <em>it</em> <code>a&lt;b</code>"
+        );
+        // an unmatched marker stays as it is
+        assert_eq!(inline("2 * 3"), "2 * 3");
+    }
+
+    #[test]
+    fn converts_lists_and_image_descriptions() {
+        let html = markdown_to_html("- a\n- b\n1. c\n<image>\nA chart\n</image>");
+        assert_eq!(
+            html,
+            "<ul>\n<li>a</li>\n<li>b</li>\n</ul>\n<ol>\n<li>c</li>\n</ol>\n\
+             <figure class=\"description\">\n<p>A chart</p>\n</figure>\n"
+        );
+    }
+
+    #[test]
+    fn links_images_relative_to_the_page() {
+        assert_eq!(
+            relative_to(Path::new("/out/img/p1.png"), Path::new("/out/review")),
+            Path::new("../img/p1.png")
+        );
+        assert_eq!(
+            relative_to(Path::new("/out/p1.png"), Path::new("/out")),
+            Path::new("p1.png")
+        );
+        let pages = [ReviewPage {
+            page_no: 1,
+            images: vec![PathBuf::from("/out/img/p1.png")],
+            markdown: String::new(),
+        }];
+        let html = render("a & b", Path::new("/out/review/index.html"), &pages);
+        assert!(html.contains("<title>a &amp; b</title>"));
+        assert!(html.contains("<img src=\"../img/p1.png\" alt=\"page 1\">"));
+    }
+}
@@ -0,0 +1,157 @@
+use serde_json::Value;
+
+/// Where `value` breaks `schema`, as "path: problem" lines. Covers the JSON
+/// Schema keywords Ollama's structured outputs use: type, properties,
+/// required, additionalProperties, items and enum. Other keywords are
+/// ignored.
+pub fn violations(schema: &Value, value: &Value) -> Vec<String> {
+    let mut found = Vec::new();
+    check(schema, value, "$", &mut found);
+    found
+}
+
+fn check(schema: &Value, value: &Value, path: &str, found: &mut Vec<String>) {
+    let Some(schema) = schema.as_object() else {
+        return;
+    };
+
+    if let Some(expected) = schema.get("type") {
+        let types = match expected {
+            Value::Array(types) => types.iter().filter_map(Value::as_str).collect(),
+            _ => expected.as_str().into_iter().collect::<Vec<_>>(),
+        };
+        if !types.is_empty() && !types.iter().any(|name| has_type(value, name)) {
+            found.push(format!(
+                "{}: expected {}, got {}",
+                path,
+                types.join(" or "),
+                type_name(value)
+            ));
+            return;
+        }
+    }
+
+    if let Some(allowed) = schema.get("enum").and_then(Value::as_array)
+        && !allowed.contains(value)
+    {
+        found.push(format!(
+            "{}: {} is not one of {}",
+            path,
+            value,
+            Value::from(allowed.clone())
+        ));
+    }
+
+    if let Some(object) = value.as_object() {
+        let properties = schema.get("properties").and_then(Value::as_object);
+        for name in schema
+            .get("required")
+            .and_then(Value::as_array)
+            .into_iter()
+            .flatten()
+            .filter_map(Value::as_str)
+        {
+            if !object.contains_key(name) {
+                found.push(format!("{}: missing required property {:?}", path, name));
+            }
+        }
+        for (name, field) in object {
+            match properties.and_then(|properties| properties.get(name)) {
+                Some(field_schema) => {
+                    check(field_schema, field, &format!("{}.{}", path, name), found)
+                }
+                None if schema.get("additionalProperties") == Some(&Value::Bool(false)) => {
+                    found.push(format!("{}: unexpected property {:?}", path, name));
+                }
+                None => {}
+            }
+        }
+    }
+
+    if let Some(items) = value.as_array()
+        && let Some(item_schema) = schema.get("items")
+    {
+        for (i, item) in items.iter().enumerate() {
+            check(item_schema, item, &format!("{}[{}]", path, i), found);
+        }
+    }
+}
+
+fn has_type(value: &Value, name: &str) -> bool {
+    match name {
+        "object" => value.is_object(),
+        "array" => value.is_array(),
+        "string" => value.is_string(),
+        "number" => value.is_number(),
+        "integer" => value.is_i64() || value.is_u64(),
+        "boolean" => value.is_boolean(),
+        "null" => value.is_null(),
+        _ => true,
+    }
+}
+
+fn type_name(value: &Value) -> &'static str {
+    match value {
+        Value::Object(_) => "object",
+        Value::Array(_) => "array",
+        Value::String(_) => "string",
+        Value::Number(_) => "number",
+        Value::Bool(_) => "boolean",
+        Value::Null => "null",
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn schema() -> Value {
+        json!({
+            "type": "object",
+            "properties": {
+                "title": {"type": "string"},
+                "kind": {"enum": ["article", "table"]},
+                "lines": {"type": "array", "items": {"type": "string"}},
+                "page": {"type": ["integer", "null"]}
+            },
+            "required": ["title", "lines"],
+            "additionalProperties": false
+        })
+    }
+
+    #[test]
+    fn conforming_value_has_no_violations() {
+        let value = json!({"title": "A", "kind": "table", "lines": ["x", "y"], "page": null});
+        assert!(violations(&schema(), &value).is_empty());
+    }
+
+    #[test]
+    fn reports_each_violation_with_its_path() {
+        let value = json!({"kind": "poem", "lines": ["x", 2], "page": 1.5, "extra": true});
+        assert_eq!(
+            violations(&schema(), &value),
+            [
+                "$: missing required property \"title\"",
+                "$: unexpected property \"extra\"",
+                "$.kind: \"poem\" is not one of [\"article\",\"table\"]",
+                "$.lines[1]: expected string, got number",
+                "$.page: expected integer or null, got number",
+            ]
+        );
+    }
+
+    #[test]
+    fn wrong_top_level_type_stops_there() {
+        assert_eq!(
+            violations(&schema(), &json!("text")),
+            ["$: expected object, got string"]
+        );
+    }
+
+    #[test]
+    fn unknown_keywords_are_ignored() {
+        let schema = json!({"type": "string", "minLength": 10, "format": "date"});
+        assert!(violations(&schema, &json!("short")).is_empty());
+    }
+}
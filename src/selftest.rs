@@ -0,0 +1,89 @@
+use anyhow::Result;
+use futures_util::TryStreamExt;
+use pdfium_render::prelude::*;
+use std::time::Instant;
+
+use crate::args::Args;
+use crate::ollama::{ChatMessage, OllamaClient, Role};
+
+const SAMPLE_LINES: [&str; 3] = [
+    "pdftopng-rs self-test",
+    "The quick brown fox jumps over the lazy dog.",
+    "0123456789",
+];
+
+/// Build a one-page PDF in memory, render it and send it to `ollama`.
+/// Returns whether the model produced a transcription.
+pub async fn run(
+    args: &Args,
+    prompt: &str,
+    pdfium: &Pdfium,
+    ollama: &OllamaClient,
+) -> Result<bool> {
+    let start = Instant::now();
+    let buffer = {
+        let mut document = pdfium.create_new_pdf()?;
+        let font = document.fonts_mut().helvetica();
+        let mut page = document
+            .pages_mut()
+            .create_page_at_end(PdfPagePaperSize::a4())?;
+        for (i, line) in SAMPLE_LINES.iter().enumerate() {
+            let y = PdfPoints::new(760.0 - 40.0 * i as f32);
+            page.objects_mut().create_text_object(
+                PdfPoints::new(72.0),
+                y,
+                line,
+                font,
+                PdfPoints::new(24.0),
+            )?;
+        }
+        crate::render_page(args, prompt, &page, 1, 1.0)?
+            .ok_or_else(|| anyhow::anyhow!("The sample page rendered blank"))?
+    };
+    println!(
+        "Rendered sample page ({} bytes) in {:?}",
+        buffer.len(),
+        start.elapsed()
+    );
+
+    let buffer = match args.bit_depth {
+        crate::render::BitDepth::Eight => buffer,
+        crate::render::BitDepth::Sixteen => crate::render::eight_bit_png(&buffer)?,
+    };
+    let base64 = base64::Engine::encode(&base64::engine::general_purpose::STANDARD, &buffer);
+    let messages = vec![ChatMessage {
+        role: Role::User,
+        content: prompt.to_string(),
+        thinking: None,
+        images: Some(vec![base64]),
+    }];
+    let options = crate::generate_options(args);
+
+    println!(
+        "Sending sample page to {} ({})",
+        ollama.url(),
+        ollama.model()
+    );
+    let start = Instant::now();
+    let mut stream = ollama.generate_stream(&messages, &options);
+    let mut transcription = String::new();
+    while let Some(response) = stream.try_next().await? {
+        transcription += &response.message.content;
+    }
+    println!(
+        "Model answered in {:?}:\n{}",
+        start.elapsed(),
+        transcription
+    );
+
+    let ok = !transcription.trim().is_empty();
+    println!(
+        "Self-test {}",
+        if ok {
+            "passed"
+        } else {
+            "failed: empty transcription"
+        }
+    );
+    Ok(ok)
+}